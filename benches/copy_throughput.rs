@@ -0,0 +1,151 @@
+use std::time::{Duration, Instant};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+// the data paths relay::relay ships, included as they are; the bench reads no byte counts
+#[allow(dead_code)]
+#[path = "../src/copy.rs"]
+mod copy;
+#[path = "../src/obfuscate.rs"]
+mod obfuscate;
+#[cfg(target_os = "linux")]
+#[path = "../src/splice.rs"]
+mod splice;
+
+const TRANSFER_SIZE: usize = 512 * 1024 * 1024;
+const BUFFER_SIZES: [usize; 2] = [8 * 1024, 256 * 1024];
+
+#[derive(Clone, Copy)]
+enum DataPath {
+    Copy,
+    #[cfg(target_os = "linux")]
+    Splice,
+}
+
+impl DataPath {
+    const ALL: &[DataPath] = &[
+        DataPath::Copy,
+        #[cfg(target_os = "linux")]
+        DataPath::Splice,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            DataPath::Copy => "copy",
+            #[cfg(target_os = "linux")]
+            DataPath::Splice => "splice",
+        }
+    }
+
+    async fn relay(
+        self,
+        client: &mut TcpStream,
+        server: &mut TcpStream,
+        buffer_size: usize,
+    ) -> std::io::Result<()> {
+        match self {
+            DataPath::Copy => {
+                let transferred = copy::Transferred::default();
+                copy::copy_bidirectional(client, server, buffer_size, None, &transferred).await?;
+            }
+            #[cfg(target_os = "linux")]
+            DataPath::Splice => {
+                let pipes = splice::Pipes::new(buffer_size)?;
+                let (up, down) = Default::default();
+                splice::splice_bidirectional(client, server, pipes, &up, &down).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// user plus system time of the whole process, sender and sink included; they do the same
+// work for every data path, so differences come from the relay
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let micros = |t: libc::timeval| t.tv_sec as u64 * 1_000_000 + t.tv_usec as u64;
+    Some(Duration::from_micros(
+        micros(usage.ru_utime) + micros(usage.ru_stime),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+async fn run(path: DataPath, buffer_size: usize) -> std::io::Result<(f64, Option<Duration>)> {
+    let upstream = TcpListener::bind("127.0.0.1:0").await?;
+    let upstream_addr = upstream.local_addr()?;
+    let proxy = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy.local_addr()?;
+
+    let sink = tokio::spawn(async move {
+        let (mut stream, _) = upstream.accept().await?;
+        let mut buf = vec![0u8; 256 * 1024];
+        let mut total = 0;
+        while total < TRANSFER_SIZE {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            total += n;
+        }
+        Ok::<usize, std::io::Error>(total)
+    });
+
+    tokio::spawn(async move {
+        let (mut client, _) = proxy.accept().await?;
+        let mut server = TcpStream::connect(upstream_addr).await?;
+        path.relay(&mut client, &mut server, buffer_size).await
+    });
+
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    let chunk = vec![0x5Au8; 64 * 1024];
+
+    let cpu_before = cpu_time();
+    let started = Instant::now();
+    let mut sent = 0;
+    while sent < TRANSFER_SIZE {
+        client.write_all(&chunk).await?;
+        sent += chunk.len();
+    }
+    client.shutdown().await?;
+
+    let received = sink.await??;
+    let elapsed = started.elapsed().as_secs_f64();
+    let cpu = cpu_before
+        .zip(cpu_time())
+        .map(|(before, after)| after - before);
+
+    Ok((received as f64 / elapsed / (1024.0 * 1024.0), cpu))
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    for &path in DataPath::ALL {
+        for buffer_size in BUFFER_SIZES {
+            let (throughput, cpu) = run(path, buffer_size).await?;
+            let cpu = cpu.map_or("n/a".to_string(), |cpu| {
+                format!("{:.2}s", cpu.as_secs_f64())
+            });
+            println!(
+                "{:>6}, copy_buffer_size = {:>6} KiB: {:>8.1} MiB/s, CPU {}",
+                path.name(),
+                buffer_size / 1024,
+                throughput,
+                cpu
+            );
+        }
+    }
+
+    Ok(())
+}
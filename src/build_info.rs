@@ -0,0 +1,23 @@
+use chrono::DateTime;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_SHA: &str = env!("BEDROCKHOLE_GIT_SHA");
+const BUILD_TIMESTAMP: &str = env!("BEDROCKHOLE_BUILD_TIMESTAMP");
+
+pub fn build_date() -> String {
+    BUILD_TIMESTAMP
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+pub fn describe() -> String {
+    format!(
+        "BedrockHole {} (commit {}, built {})",
+        VERSION,
+        GIT_SHA,
+        build_date()
+    )
+}
@@ -0,0 +1,138 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+// an address block such as 203.0.113.0/24; a bare address covers just itself
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // dual-stack listeners report IPv4 clients as mapped IPv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value.as_str(), None),
+        };
+        let parsed: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("Invalid address in {}", value))?;
+        // a v4-mapped block is an IPv4 block, its prefix counts the 96 mapping bits too
+        let addr = parsed.to_canonical();
+        let max = if parsed.is_ipv4() { 32 } else { 128 };
+        let offset = if parsed.is_ipv6() && addr.is_ipv4() {
+            96
+        } else {
+            0
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .map_err(|_| anyhow!("Invalid prefix length in {}", value))?,
+            None => max,
+        };
+        if prefix > max {
+            bail!("Prefix length of {} exceeds {}", value, max);
+        }
+        if prefix < offset {
+            bail!(
+                "Prefix length of {} reaches beyond the IPv4-mapped range",
+                value
+            );
+        }
+        Ok(Self {
+            addr,
+            prefix: prefix - offset,
+        })
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(value: Cidr) -> Self {
+        format!("{}/{}", value.addr, value.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        Cidr::try_from(s.to_string()).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn contains_matches_ipv4_blocks() {
+        let block = cidr("203.0.113.0/24");
+        assert!(block.contains(ip("203.0.113.0")));
+        assert!(block.contains(ip("203.0.113.255")));
+        assert!(!block.contains(ip("203.0.114.1")));
+        // dual-stack listeners see IPv4 clients as mapped addresses
+        assert!(block.contains(ip("::ffff:203.0.113.7")));
+        assert!(!block.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn contains_matches_ipv6_blocks() {
+        let block = cidr("2001:db8::/32");
+        assert!(block.contains(ip("2001:db8:ffff::1")));
+        assert!(!block.contains(ip("2001:db9::1")));
+        assert!(!block.contains(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn zero_prefix_matches_its_whole_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("198.51.100.1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn bare_address_covers_only_itself() {
+        let single = cidr("198.51.100.1");
+        assert!(single.contains(ip("198.51.100.1")));
+        assert!(!single.contains(ip("198.51.100.2")));
+        assert!(cidr("2001:db8::1").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn mapped_block_becomes_the_ipv4_block() {
+        let block = cidr("::ffff:203.0.113.0/120");
+        assert!(block == cidr("203.0.113.0/24"));
+        assert!(block.contains(ip("203.0.113.7")));
+        assert!(!block.contains(ip("203.0.114.7")));
+
+        assert!(cidr("::ffff:198.51.100.1") == cidr("198.51.100.1"));
+        assert!(Cidr::try_from("::ffff:203.0.113.0/64".to_string()).is_err());
+        assert!(Cidr::try_from("203.0.113.0/33".to_string()).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::config::CircuitBreakerConfig;
+
+enum State {
+    Closed,
+    Open { until: Instant },
+    // a single trial connection decides whether the circuit closes again
+    HalfOpen { trial_running: bool },
+}
+
+struct Inner {
+    state: State,
+    failures: VecDeque<Instant>,
+}
+
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                failures: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn allow(&self, name: &str) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Closed => true,
+            State::Open { until } if Instant::now() >= until => {
+                tracing::info!("Circuit for {} half-open, testing recovery", name);
+                inner.state = State::HalfOpen {
+                    trial_running: true,
+                };
+                true
+            }
+            State::Open { .. } => false,
+            State::HalfOpen {
+                ref mut trial_running,
+            } => !std::mem::replace(trial_running, true),
+        }
+    }
+
+    pub fn on_success(&self, name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if !matches!(inner.state, State::Closed) {
+            tracing::info!("Circuit for {} closed", name);
+        }
+        inner.state = State::Closed;
+        inner.failures.clear();
+    }
+
+    pub fn on_failure(&self, name: &str) {
+        if !self.config.enabled {
+            return;
+        }
+
+        let now = Instant::now();
+        let cooldown = Duration::from_secs(self.config.cooldown);
+        let window = Duration::from_secs(self.config.window);
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::HalfOpen { .. } => {
+                tracing::warn!(
+                    "Circuit for {} re-opened, recovery test failed; rejecting connections for {}s",
+                    name,
+                    self.config.cooldown
+                );
+                inner.state = State::Open {
+                    until: now + cooldown,
+                };
+            }
+            State::Open { .. } => {}
+            State::Closed => {
+                inner.failures.push_back(now);
+                while inner
+                    .failures
+                    .front()
+                    .is_some_and(|&at| now.duration_since(at) > window)
+                {
+                    inner.failures.pop_front();
+                }
+                if inner.failures.len() >= self.config.failures as usize {
+                    tracing::warn!(
+                        "Circuit for {} opened after {} failures within {}s; rejecting connections for {}s",
+                        name,
+                        inner.failures.len(),
+                        self.config.window,
+                        self.config.cooldown
+                    );
+                    inner.failures.clear();
+                    inner.state = State::Open {
+                        until: now + cooldown,
+                    };
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail};
+
+use crate::config::ConfigSource;
+
+#[derive(Default)]
+pub struct Args {
+    pub config: Option<ConfigSource>,
+    pub gen_config: Option<PathBuf>,
+    pub worker_threads: Option<usize>,
+    pub version: bool,
+    pub dry_run: bool,
+}
+
+impl Args {
+    pub fn parse() -> anyhow::Result<Self> {
+        let mut res = Self::default();
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    let source = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--config requires a path, URL or -"))?;
+                    res.config = Some(ConfigSource::parse(&source));
+                }
+                "--gen-config" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--gen-config requires a path"))?;
+                    res.gen_config = Some(PathBuf::from(path));
+                }
+                "--worker-threads" => {
+                    let threads = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--worker-threads requires a number"))?;
+                    match threads.parse() {
+                        Ok(n) if n > 0 => res.worker_threads = Some(n),
+                        _ => bail!("Invalid --worker-threads value: {}", threads),
+                    }
+                }
+                "--version" | "-V" => res.version = true,
+                "--dry-run" => res.dry_run = true,
+                other => bail!("Unknown argument: {}", other),
+            }
+        }
+
+        Ok(res)
+    }
+}
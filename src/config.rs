@@ -1,49 +1,658 @@
-use std::{fs, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::{cidr::Cidr, socks5::Socks5Proxy};
+
+// where --config points: a file, "-" for stdin, or an http(s) URL
+#[derive(Clone)]
+pub enum ConfigSource {
+    File(PathBuf),
+    Stdin,
+    Url(String),
+}
+
+impl ConfigSource {
+    pub fn parse(arg: &str) -> Self {
+        if arg == "-" {
+            ConfigSource::Stdin
+        } else if arg.starts_with("https://") || arg.starts_with("http://") {
+            ConfigSource::Url(arg.to_string())
+        } else {
+            ConfigSource::File(PathBuf::from(arg))
+        }
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::File(path) => write!(f, "{}", path.display()),
+            ConfigSource::Stdin => write!(f, "<stdin>"),
+            ConfigSource::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum DDNSProvider {
+    #[default]
     Cloudflare,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordMode {
+    #[default]
+    A,
+    Cname,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum HAProxyVersion {
     V1,
+    #[default]
     V2,
 }
 
+#[derive(Serialize, Deserialize, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Traversal {
+    #[default]
+    Stun,
+    Upnp,
+    Natpmp,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    Random,
+    LeastConnections,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelMode {
+    #[default]
+    Plain,
+    Encrypted,
+}
+
+// which end of the tunnel this instance is: the edge dials the home instance as its
+// upstream, the home instance accepts the edge as a client
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelRole {
+    Edge,
+    Home,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct DDNSConfig {
+    #[serde(default)]
     pub provider: DDNSProvider,
     pub token: String,
     pub domain: String,
+    #[serde(default = "default_sub_domain")]
     pub sub_domain: String,
+    #[serde(default = "default_ttl")]
+    pub ttl: u32,
+    #[serde(default = "default_enabled")]
+    pub manage_a: bool,
+    #[serde(default = "default_enabled")]
+    pub manage_srv: bool,
+    #[serde(default)]
+    pub additional_addresses: Vec<Ipv4Addr>,
+    #[serde(default)]
+    pub remove_on_shutdown: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub force: bool,
+    #[serde(default)]
+    pub record_mode: RecordMode,
+    #[serde(default)]
+    pub cname_target: Option<String>,
+    #[serde(default)]
+    pub txt_content: Option<String>,
+    // relative to domain like sub_domain, which it defaults to
+    #[serde(default)]
+    pub txt_name: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeepaliveConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_keepalive_time")]
+    pub time: u64,
+    #[serde(default = "default_keepalive_interval")]
+    pub interval: u64,
+    #[serde(default = "default_keepalive_retries")]
+    pub retries: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct CircuitBreakerConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_circuit_failures")]
+    pub failures: u32,
+    #[serde(default = "default_circuit_window")]
+    pub window: u64,
+    #[serde(default = "default_circuit_cooldown")]
+    pub cooldown: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TunnelConfig {
+    #[serde(default)]
+    pub mode: TunnelMode,
+    pub role: TunnelRole,
+    #[serde(default)]
+    pub psk: Option<String>,
+    #[serde(default)]
+    pub compress: bool,
+    #[serde(default = "default_compression_level")]
+    pub compression_level: i32,
+    #[serde(default)]
+    pub mux: bool,
+    #[serde(default = "default_mux_connections")]
+    pub mux_connections: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StunServer {
+    pub host: String,
+    #[serde(default = "default_stun_server_port")]
+    pub port: u16,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpstreamConfig {
+    pub host: String,
+    #[serde(default = "default_server_port")]
+    pub port: u16,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ForwardConfig {
+    #[serde(default)]
+    pub bind_addr: Option<IpAddr>,
+    #[serde(default = "default_local_port")]
     pub local_port: u16,
+    #[serde(default)]
     pub server_host: String,
+    #[serde(default = "default_server_port")]
     pub server_port: u16,
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamConfig>,
+    #[serde(default)]
+    pub routes: HashMap<String, UpstreamConfig>,
+    #[serde(default)]
+    pub balance: BalanceStrategy,
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    #[serde(default = "default_health_probe_interval")]
+    pub health_probe_interval: u64,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
     pub haproxy_support: bool,
+    #[serde(default)]
     pub haproxy_version: HAProxyVersion,
+    #[serde(default = "default_copy_buffer_size")]
+    pub copy_buffer_size: usize,
+    // off until copy_throughput shows it beats the buffered copy on the target machine
+    #[serde(default)]
+    pub splice: bool,
+    #[serde(default)]
+    pub keepalive: KeepaliveConfig,
+    #[serde(default = "default_listen_backlog")]
+    pub listen_backlog: u32,
+    #[serde(default)]
+    pub max_accept_rate: Option<u32>,
+    #[serde(default)]
+    pub max_active_per_ip: Option<u32>,
+    #[serde(default)]
+    pub geoip_database: Option<PathBuf>,
+    #[serde(default)]
+    pub allowed_countries: Option<Vec<String>>,
+    #[serde(default)]
+    pub upstream_proxy: Option<Socks5Proxy>,
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+    // seconds, 0 leaves sessions unlimited
+    #[serde(default)]
+    pub max_session_duration: u64,
+    // seconds the old listener's sessions may keep running after a reload
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    #[serde(default)]
+    pub dns_server: Option<SocketAddr>,
+    #[serde(default)]
+    pub heartbeat_magic: Option<String>,
+    #[serde(default)]
+    pub heartbeat_secret: Option<String>,
+    // besides the WAN address, sources whose connections are checked for heartbeats
+    #[serde(default)]
+    pub heartbeat_sources: Vec<Cidr>,
+    #[serde(default)]
+    pub obfuscation_key: Option<String>,
+    #[serde(default)]
+    pub tunnel: Option<TunnelConfig>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct GeneralConfig {
-    pub heartbeat: u64,
+    #[serde(default = "default_binding_keepalive_interval", alias = "heartbeat")]
+    pub binding_keepalive_interval: u64,
+    // TCP keepalive of the STUN connections and the heartbeat through the public address
+    #[serde(default)]
+    pub stun_keepalive: KeepaliveConfig,
+    #[serde(default = "default_address_check_interval")]
+    pub address_check_interval: u64,
+    #[serde(default)]
+    pub traversal: Traversal,
+    #[serde(default = "default_mapping_lease")]
+    pub mapping_lease: u32,
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+    #[serde(default = "default_stun_server_host")]
     pub stun_server_host: String,
+    #[serde(default = "default_stun_server_port")]
     pub stun_server_port: u16,
+    // raced against stun_server_host, the fastest answer wins
+    #[serde(default)]
+    pub stun_servers: Vec<StunServer>,
+    // a new mapping needs a second server's agreement before it is published
+    #[serde(default)]
+    pub stun_confirm: bool,
+    #[serde(default = "default_enabled")]
+    pub stun_software: bool,
+    #[serde(default)]
+    pub ipv6: bool,
+    #[serde(default)]
+    pub instance_name: Option<String>,
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
+    #[serde(default)]
+    pub stun_username: Option<String>,
+    #[serde(default)]
+    pub stun_password: Option<String>,
+    #[serde(default)]
+    pub stun_realm: Option<String>,
+    #[serde(default)]
+    pub nat_check_server_host: Option<String>,
+    #[serde(default = "default_nat_check_server_port")]
+    pub nat_check_server_port: u16,
+    #[serde(default = "default_state_file")]
+    pub state_file: PathBuf,
+    // re-read the records at startup before trusting state_file
+    #[serde(default = "default_enabled")]
+    pub verify_state: bool,
+    #[serde(default)]
+    pub pid_file: Option<PathBuf>,
+    #[serde(default)]
+    pub status_port: Option<u16>,
+    #[serde(default = "default_status_bind")]
+    pub status_bind: IpAddr,
+    #[serde(default = "default_health_window")]
+    pub health_window: u64,
+    #[serde(default)]
+    pub statsd_addr: Option<String>,
+    #[serde(default = "default_statsd_interval")]
+    pub statsd_interval: u64,
+    // seconds between summary log lines, 0 turns them off
+    #[serde(default)]
+    pub stats_log_interval: u64,
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    #[serde(default = "default_flap_threshold")]
+    pub flap_threshold: u32,
+    #[serde(default = "default_flap_window")]
+    pub flap_window: u64,
+    #[serde(default)]
+    pub reachability_proxy: Option<Socks5Proxy>,
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TurnConfig {
+    pub server_host: String,
+    #[serde(default = "default_stun_server_port")]
+    pub server_port: u16,
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub realm: Option<String>,
+    #[serde(default = "default_turn_lifetime")]
+    pub lifetime: u32,
+    #[serde(default)]
+    pub peers: Vec<Ipv4Addr>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ServicesConfig {
+    #[serde(default = "default_enabled")]
+    pub forward: bool,
+    #[serde(default = "default_enabled")]
+    pub stun: bool,
+    #[serde(default = "default_enabled")]
+    pub ddns: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct BHConfig {
-    pub ddns: DDNSConfig,
+    #[serde(default)]
+    pub ddns: Option<DDNSConfig>,
+    #[serde(default)]
     pub forward: ForwardConfig,
+    #[serde(default)]
     pub general: GeneralConfig,
+    #[serde(default)]
+    pub services: ServicesConfig,
+    #[serde(default)]
+    pub turn: Option<TurnConfig>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_sub_domain() -> String {
+    "@".to_string()
+}
+
+fn default_ttl() -> u32 {
+    60
+}
+
+fn default_local_port() -> u16 {
+    25566
+}
+
+fn default_server_port() -> u16 {
+    25565
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+fn default_health_probe_interval() -> u64 {
+    10
+}
+
+fn default_circuit_failures() -> u32 {
+    5
+}
+
+fn default_circuit_window() -> u64 {
+    60
+}
+
+fn default_circuit_cooldown() -> u64 {
+    30
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_mux_connections() -> usize {
+    1
+}
+
+fn default_copy_buffer_size() -> usize {
+    8 * 1024
+}
+
+fn default_listen_backlog() -> u32 {
+    1024
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_keepalive_time() -> u64 {
+    60
+}
+
+fn default_keepalive_interval() -> u64 {
+    10
+}
+
+fn default_keepalive_retries() -> u32 {
+    3
+}
+
+fn default_address_check_interval() -> u64 {
+    300
+}
+
+fn default_binding_keepalive_interval() -> u64 {
+    10
+}
+
+fn default_stun_server_host() -> String {
+    "stun.hot-chilli.net".to_string()
+}
+
+fn default_stun_server_port() -> u16 {
+    3478
+}
+
+fn default_mapping_lease() -> u32 {
+    3600
+}
+
+fn default_turn_lifetime() -> u32 {
+    600
+}
+
+fn default_nat_check_server_port() -> u16 {
+    3479
+}
+
+fn default_state_file() -> PathBuf {
+    PathBuf::from("state.json")
+}
+
+fn default_health_window() -> u64 {
+    300
+}
+
+fn default_statsd_interval() -> u64 {
+    10
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_flap_threshold() -> u32 {
+    5
+}
+
+fn default_flap_window() -> u64 {
+    3600
+}
+
+fn default_status_bind() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+impl Default for BHConfig {
+    fn default() -> Self {
+        Self {
+            ddns: Some(DDNSConfig::default()),
+            forward: ForwardConfig::default(),
+            general: GeneralConfig::default(),
+            services: ServicesConfig::default(),
+            turn: None,
+        }
+    }
+}
+
+impl Default for ServicesConfig {
+    fn default() -> Self {
+        Self {
+            forward: default_enabled(),
+            stun: default_enabled(),
+            ddns: default_enabled(),
+        }
+    }
+}
+
+impl Default for DDNSConfig {
+    fn default() -> Self {
+        Self {
+            provider: DDNSProvider::default(),
+            token: "your-cloudflare-api-token".to_string(),
+            domain: "example.com".to_string(),
+            sub_domain: "mc".to_string(),
+            ttl: default_ttl(),
+            manage_a: default_enabled(),
+            manage_srv: default_enabled(),
+            additional_addresses: Vec::new(),
+            remove_on_shutdown: false,
+            tags: Vec::new(),
+            force: false,
+            record_mode: RecordMode::default(),
+            cname_target: None,
+            txt_content: None,
+            txt_name: None,
+        }
+    }
+}
+
+impl Default for ForwardConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: None,
+            local_port: default_local_port(),
+            server_host: "127.0.0.1".to_string(),
+            server_port: default_server_port(),
+            upstreams: Vec::new(),
+            routes: HashMap::new(),
+            balance: BalanceStrategy::default(),
+            unhealthy_threshold: default_unhealthy_threshold(),
+            health_probe_interval: default_health_probe_interval(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            haproxy_support: false,
+            haproxy_version: HAProxyVersion::default(),
+            copy_buffer_size: default_copy_buffer_size(),
+            splice: false,
+            keepalive: KeepaliveConfig::default(),
+            listen_backlog: default_listen_backlog(),
+            max_accept_rate: None,
+            max_active_per_ip: None,
+            geoip_database: None,
+            allowed_countries: None,
+            upstream_proxy: None,
+            accept_proxy_protocol: false,
+            max_session_duration: 0,
+            drain_timeout: default_drain_timeout(),
+            dns_server: None,
+            heartbeat_magic: None,
+            heartbeat_secret: None,
+            heartbeat_sources: Vec::new(),
+            obfuscation_key: None,
+            tunnel: None,
+        }
+    }
+}
+
+impl ForwardConfig {
+    // a bare server_host/server_port pair is a pool of one
+    pub fn targets(&self) -> Vec<UpstreamConfig> {
+        if self.upstreams.is_empty() {
+            vec![UpstreamConfig {
+                host: self.server_host.clone(),
+                port: self.server_port,
+            }]
+        } else {
+            self.upstreams.clone()
+        }
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            failures: default_circuit_failures(),
+            window: default_circuit_window(),
+            cooldown: default_circuit_cooldown(),
+        }
+    }
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            time: default_keepalive_time(),
+            interval: default_keepalive_interval(),
+            retries: default_keepalive_retries(),
+        }
+    }
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            binding_keepalive_interval: default_binding_keepalive_interval(),
+            stun_keepalive: KeepaliveConfig::default(),
+            address_check_interval: default_address_check_interval(),
+            traversal: Traversal::default(),
+            mapping_lease: default_mapping_lease(),
+            gateway: None,
+            stun_server_host: default_stun_server_host(),
+            stun_server_port: default_stun_server_port(),
+            stun_servers: Vec::new(),
+            stun_confirm: false,
+            stun_software: default_enabled(),
+            ipv6: false,
+            instance_name: None,
+            worker_threads: None,
+            stun_username: None,
+            stun_password: None,
+            stun_realm: None,
+            nat_check_server_host: None,
+            nat_check_server_port: default_nat_check_server_port(),
+            state_file: default_state_file(),
+            verify_state: default_enabled(),
+            pid_file: None,
+            status_port: None,
+            status_bind: default_status_bind(),
+            health_window: default_health_window(),
+            statsd_addr: None,
+            statsd_interval: default_statsd_interval(),
+            stats_log_interval: 0,
+            log_level: default_log_level(),
+            otlp_endpoint: None,
+            flap_threshold: default_flap_threshold(),
+            flap_window: default_flap_window(),
+            reachability_proxy: None,
+        }
+    }
 }
 
 impl BHConfig {
@@ -55,9 +664,482 @@ impl BHConfig {
         Ok(res)
     }
 
+    pub fn load(source: &ConfigSource) -> anyhow::Result<Self> {
+        let buf = match source {
+            ConfigSource::File(path) => return Self::load_from_path(path),
+            ConfigSource::Stdin => {
+                let mut buf = Vec::new();
+                std::io::stdin().read_to_end(&mut buf)?;
+                buf
+            }
+            // called before the main runtime exists, whose size may come from this very file
+            ConfigSource::Url(url) => tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(fetch(url))?,
+        };
+
+        let res = serde_json::from_slice(&buf)?;
+
+        Ok(res)
+    }
+
+    pub fn write_to_path(&self, path: &PathBuf) -> anyhow::Result<()> {
+        let buf = serde_json::to_vec_pretty(self)?;
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.write_all(&buf)?;
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.services.forward && !self.services.stun {
+            bail!("At least one of services.forward and services.stun must be enabled");
+        }
+        if self.services.ddns && !self.services.stun && self.ddns.is_some() {
+            bail!("services.ddns requires services.stun to detect the address to publish");
+        }
+
+        // every service section may be left out or kept stale while its service is off
+        if self.services.ddns
+            && let Some(ddns) = &self.ddns
+        {
+            Self::validate_ddns(ddns)?;
+        }
+
+        if let Some(turn) = &self.turn {
+            if !self.services.stun || !self.services.forward {
+                bail!("turn requires both services.stun and services.forward");
+            }
+            if self.general.traversal != Traversal::Stun {
+                bail!("turn is only used as a fallback for general.traversal = \"stun\"");
+            }
+            if turn.server_host.trim().is_empty() {
+                bail!("turn.server_host must not be empty");
+            }
+            if turn.server_port == 0 {
+                bail!("turn.server_port must be between 1 and 65535");
+            }
+            if turn.username.is_empty() {
+                bail!("turn.username must not be empty");
+            }
+            if turn.lifetime < 60 {
+                bail!("turn.lifetime must be at least 60 seconds");
+            }
+        }
+
+        if self.services.forward {
+            self.validate_forward()?;
+        }
+        if self.services.stun {
+            self.validate_stun()?;
+        }
+
+        if self.general.worker_threads == Some(0) {
+            bail!("general.worker_threads must be greater than zero");
+        }
+        if let Some(name) = &self.general.instance_name
+            && !(1..=64).contains(&name.len())
+        {
+            bail!("general.instance_name must be between 1 and 64 bytes long");
+        }
+        if self.general.status_port == Some(0) {
+            bail!("general.status_port must be between 1 and 65535");
+        }
+        if self.services.forward && self.general.status_port == Some(self.forward.local_port) {
+            bail!("general.status_port collides with forward.local_port");
+        }
+        if self.general.health_window == 0 {
+            bail!("general.health_window must be greater than 0");
+        }
+        if self.general.statsd_addr.as_deref() == Some("") {
+            bail!("general.statsd_addr must not be empty");
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.general.log_level) {
+            bail!(
+                "general.log_level {:?} is not a valid filter: {}",
+                self.general.log_level,
+                e
+            );
+        }
+        if self.general.statsd_interval == 0 {
+            bail!("general.statsd_interval must be greater than 0");
+        }
+        if self.general.otlp_endpoint.as_deref() == Some("") {
+            bail!("general.otlp_endpoint must not be empty");
+        }
+        if self.general.flap_threshold < 2 {
+            bail!("general.flap_threshold must be at least 2");
+        }
+        if self.general.flap_window == 0 {
+            bail!("general.flap_window must be greater than 0");
+        }
+        if self.general.reachability_proxy.is_some() && !self.services.forward {
+            bail!("general.reachability_proxy requires services.forward to answer the probe");
+        }
+
+        Ok(())
+    }
+
+    fn validate_ddns(ddns: &DDNSConfig) -> anyhow::Result<()> {
+        if ddns.token.trim().is_empty() {
+            bail!("ddns.token must not be empty");
+        }
+        if ddns.domain.trim().is_empty() {
+            bail!("ddns.domain must not be empty");
+        }
+        if !ddns.manage_a && !ddns.manage_srv {
+            bail!("ddns.manage_a and ddns.manage_srv must not both be false");
+        }
+        if ddns.record_mode == RecordMode::Cname {
+            let target = ddns
+                .cname_target
+                .as_deref()
+                .map(|t| t.trim().trim_end_matches('.'))
+                .filter(|t| !t.is_empty())
+                .ok_or_else(|| anyhow!("ddns.record_mode \"cname\" requires ddns.cname_target"))?;
+            let name = if ddns.sub_domain.is_empty() || ddns.sub_domain == "@" {
+                ddns.domain.clone()
+            } else {
+                format!("{}.{}", ddns.sub_domain, ddns.domain)
+            };
+            if target.eq_ignore_ascii_case(&name) {
+                bail!("ddns.cname_target must not point at {} itself", name);
+            }
+            // a CNAME cannot share its name with the extra A records
+            if !ddns.additional_addresses.is_empty() {
+                bail!(
+                    "ddns.additional_addresses cannot be combined with ddns.record_mode \"cname\""
+                );
+            }
+        } else if ddns.cname_target.is_some() {
+            bail!("ddns.cname_target is only used with ddns.record_mode \"cname\"");
+        }
+        if let Some(content) = &ddns.txt_content {
+            // Cloudflare's limit for the content of a TXT record
+            if content.is_empty() || content.len() > 2048 {
+                bail!("ddns.txt_content must be between 1 and 2048 characters");
+            }
+            let txt_name = ddns.txt_name.as_deref().unwrap_or(&ddns.sub_domain);
+            if ddns.record_mode == RecordMode::Cname && ddns.manage_a && txt_name == ddns.sub_domain
+            {
+                bail!(
+                    "ddns.txt_name must differ from ddns.sub_domain in CNAME mode, a CNAME cannot share its name"
+                );
+            }
+        } else if ddns.txt_name.is_some() {
+            bail!("ddns.txt_name requires ddns.txt_content");
+        }
+        if ddns.ttl != 1 && !(30..=86400).contains(&ddns.ttl) {
+            bail!(
+                "ddns.ttl must be 1 (automatic) or between 30 and 86400 seconds, got {}",
+                ddns.ttl
+            );
+        }
+
+        Ok(())
+    }
+
+    fn validate_stun(&self) -> anyhow::Result<()> {
+        let keepalive = &self.general.stun_keepalive;
+        if keepalive.enabled
+            && (keepalive.time == 0 || keepalive.interval == 0 || keepalive.retries == 0)
+        {
+            bail!("general.stun_keepalive time, interval and retries must be greater than zero");
+        }
+
+        if !(1..=3600).contains(&self.general.binding_keepalive_interval) {
+            bail!(
+                "general.binding_keepalive_interval must be between 1 and 3600 seconds, got {}",
+                self.general.binding_keepalive_interval
+            );
+        }
+        if !(1..=86400).contains(&self.general.address_check_interval) {
+            bail!(
+                "general.address_check_interval must be between 1 and 86400 seconds, got {}",
+                self.general.address_check_interval
+            );
+        }
+        if self.general.stun_server_host.trim().is_empty() {
+            bail!("general.stun_server_host must not be empty");
+        }
+        if self.general.stun_server_port == 0 {
+            bail!("general.stun_server_port must be between 1 and 65535");
+        }
+        for server in &self.general.stun_servers {
+            if server.host.trim().is_empty() {
+                bail!("general.stun_servers entries need a host");
+            }
+            if server.port == 0 {
+                bail!(
+                    "general.stun_servers port of {} must be between 1 and 65535",
+                    server.host
+                );
+            }
+        }
+        if self.general.stun_confirm && self.general.stun_servers.is_empty() {
+            bail!("general.stun_confirm needs a second server in general.stun_servers");
+        }
+        if self.general.stun_username.is_some() != self.general.stun_password.is_some() {
+            bail!("general.stun_username and general.stun_password must be set together");
+        }
+        if self.general.traversal != Traversal::Stun && self.general.mapping_lease == 0 {
+            bail!("general.mapping_lease must be greater than 0");
+        }
+        if self.general.nat_check_server_port == 0 {
+            bail!("general.nat_check_server_port must be between 1 and 65535");
+        }
+
+        Ok(())
+    }
+
+    fn validate_forward(&self) -> anyhow::Result<()> {
+        if self.forward.local_port == 0 {
+            bail!("forward.local_port must be between 1 and 65535");
+        }
+        if self.forward.upstreams.is_empty() && self.forward.server_host.trim().is_empty() {
+            bail!("forward.server_host must not be empty");
+        }
+        if !self.forward.routes.is_empty() {
+            // the handshake is only readable on plain connections from players
+            if self.forward.tunnel.is_some() || self.forward.obfuscation_key.is_some() {
+                bail!(
+                    "forward.routes cannot be combined with forward.tunnel or forward.obfuscation_key"
+                );
+            }
+            if self.forward.routes.keys().any(|h| h.trim().is_empty()) {
+                bail!("forward.routes hostnames must not be empty");
+            }
+        }
+        for upstream in self
+            .forward
+            .targets()
+            .into_iter()
+            .chain(self.forward.routes.values().cloned())
+        {
+            if upstream.port == 0 {
+                bail!(
+                    "upstream port for {} must be between 1 and 65535",
+                    upstream.host
+                );
+            }
+            if upstream.host.trim().is_empty() {
+                bail!("forward.upstreams entries must have a host");
+            }
+            if let Some(path) = upstream.host.strip_prefix("unix:") {
+                if cfg!(not(unix)) {
+                    bail!("unix socket upstreams are only supported on Unix");
+                }
+                if path.is_empty() {
+                    bail!("unix socket upstream {} has no path", upstream.host);
+                }
+                if let Some(tunnel) = &self.forward.tunnel
+                    && (tunnel.role == TunnelRole::Edge || tunnel.mux)
+                {
+                    bail!(
+                        "unix socket upstream {} cannot be reached through a tunnel or with tunnel.mux",
+                        upstream.host
+                    );
+                }
+            }
+            if is_local_host(&upstream.host) && upstream.port == self.forward.local_port {
+                bail!(
+                    "forward.local_port ({}) collides with the upstream {}:{} on the same host",
+                    self.forward.local_port,
+                    upstream.host,
+                    upstream.port
+                );
+            }
+        }
+
+        if !(1024..=16 * 1024 * 1024).contains(&self.forward.copy_buffer_size) {
+            bail!(
+                "forward.copy_buffer_size must be between 1 KiB and 16 MiB, got {}",
+                self.forward.copy_buffer_size
+            );
+        }
+
+        if let Some(magic) = &self.forward.heartbeat_magic
+            && !(4..=64).contains(&magic.len())
+        {
+            bail!("forward.heartbeat_magic must be between 4 and 64 bytes long");
+        }
+        if self
+            .forward
+            .heartbeat_secret
+            .as_ref()
+            .is_some_and(|s| s.is_empty())
+        {
+            bail!("forward.heartbeat_secret must not be empty");
+        }
+        if self
+            .forward
+            .obfuscation_key
+            .as_ref()
+            .is_some_and(|k| k.is_empty())
+        {
+            bail!("forward.obfuscation_key must not be empty");
+        }
+        if let Some(tunnel) = &self.forward.tunnel
+            && tunnel.compress
+            && !(1..=19).contains(&tunnel.compression_level)
+        {
+            bail!(
+                "forward.tunnel.compression_level must be between 1 and 19, got {}",
+                tunnel.compression_level
+            );
+        }
+        if let Some(tunnel) = &self.forward.tunnel {
+            if tunnel.mux && tunnel.mux_connections == 0 {
+                bail!("forward.tunnel.mux_connections must be greater than zero");
+            }
+            if tunnel.mode == TunnelMode::Encrypted
+                && tunnel.psk.as_ref().is_none_or(|psk| psk.len() < 16)
+            {
+                bail!("forward.tunnel.psk must be at least 16 bytes in encrypted mode");
+            }
+            if (tunnel.mode == TunnelMode::Encrypted || tunnel.compress)
+                && self.forward.obfuscation_key.is_some()
+            {
+                bail!(
+                    "forward.obfuscation_key cannot be combined with an encrypted or compressed tunnel"
+                );
+            }
+        }
+        if self.forward.unhealthy_threshold == 0 {
+            bail!("forward.unhealthy_threshold must be greater than zero");
+        }
+        if self.forward.health_probe_interval == 0 {
+            bail!("forward.health_probe_interval must be greater than zero");
+        }
+        let breaker = &self.forward.circuit_breaker;
+        if breaker.enabled
+            && (breaker.failures == 0 || breaker.window == 0 || breaker.cooldown == 0)
+        {
+            bail!(
+                "forward.circuit_breaker failures, window and cooldown must be greater than zero"
+            );
+        }
+        if self.forward.listen_backlog == 0 {
+            bail!("forward.listen_backlog must be greater than zero");
+        }
+        if self.forward.max_accept_rate == Some(0) {
+            bail!("forward.max_accept_rate must be greater than zero");
+        }
+        if self.forward.max_active_per_ip == Some(0) {
+            bail!("forward.max_active_per_ip must be greater than zero");
+        }
+        if let Some(countries) = &self.forward.allowed_countries {
+            if self.forward.geoip_database.is_none() {
+                bail!("forward.allowed_countries requires forward.geoip_database");
+            }
+            if let Some(code) = countries
+                .iter()
+                .find(|c| c.len() != 2 || !c.chars().all(|ch| ch.is_ascii_alphabetic()))
+            {
+                bail!(
+                    "forward.allowed_countries entry {:?} is not a two-letter ISO country code",
+                    code
+                );
+            }
+        }
+
+        let keepalive = &self.forward.keepalive;
+        if keepalive.enabled
+            && (keepalive.time == 0 || keepalive.interval == 0 || keepalive.retries == 0)
+        {
+            bail!("forward.keepalive time, interval and retries must be greater than zero");
+        }
+
+        Ok(())
+    }
+
     pub fn _default_load() -> anyhow::Result<Self> {
         let path = std::env::current_dir()?.join("config.json");
 
         Self::load_from_path(&path)
     }
 }
+
+async fn fetch(url: &str) -> anyhow::Result<Vec<u8>> {
+    let resp = crate::ddns::HTTP_CLIENT
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to fetch {}: {}", url, e))?;
+    let status = resp.status();
+    if !status.is_success() {
+        bail!("Fetching {} returned {}", url, status);
+    }
+
+    Ok(resp.bytes().await?.to_vec())
+}
+
+fn is_local_host(host: &str) -> bool {
+    if host.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| ip.is_loopback() || ip.is_unspecified())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> BHConfig {
+        let config: BHConfig = serde_json::from_str(json).unwrap();
+        config.validate().unwrap();
+        config
+    }
+
+    #[test]
+    fn ddns_only_config_needs_no_forward_section() {
+        let config = parse(
+            r#"{
+                "services": { "forward": false },
+                "ddns": { "token": "example", "domain": "example.com" }
+            }"#,
+        );
+        assert!(!config.services.forward);
+        assert!(config.ddns.is_some());
+    }
+
+    #[test]
+    fn forward_only_config_needs_no_ddns_section() {
+        let config = parse(
+            r#"{
+                "services": { "stun": false, "ddns": false },
+                "forward": { "local_port": 19132, "server_host": "127.0.0.1", "server_port": 19133 }
+            }"#,
+        );
+        assert!(!config.services.stun);
+        assert!(config.ddns.is_none());
+        assert_eq!(config.forward.local_port, 19132);
+    }
+
+    #[test]
+    fn disabled_sections_are_not_validated() {
+        parse(
+            r#"{
+                "services": { "forward": false, "ddns": false },
+                "ddns": { "token": "", "domain": "" },
+                "forward": { "local_port": 0, "server_host": "" }
+            }"#,
+        );
+        parse(
+            r#"{
+                "services": { "stun": false, "ddns": false },
+                "general": { "stun_server_host": "", "stun_server_port": 0 }
+            }"#,
+        );
+    }
+}
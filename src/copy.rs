@@ -0,0 +1,147 @@
+// the buffered copy behind relay::relay; it only needs tokio and obfuscate, so the
+// throughput bench includes it as it is
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::obfuscate::XorStream;
+
+// bytes relayed so far in each direction, kept outside the relay so that a session which
+// is cut off can still report them
+#[derive(Default)]
+pub struct Transferred {
+    pub up: AtomicU64,
+    pub down: AtomicU64,
+}
+
+impl Transferred {
+    pub fn get(&self) -> (u64, u64) {
+        (
+            self.up.load(Ordering::Relaxed),
+            self.down.load(Ordering::Relaxed),
+        )
+    }
+}
+
+// tokio::io::copy_bidirectional half-closes the same way, but has no hook to XOR the bytes
+pub async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    buffer_size: usize,
+    mut xor: Option<XorStream>,
+    total: &AtomicU64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0u8; buffer_size];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            // propagate the FIN but keep the opposite direction flowing
+            match writer.shutdown().await {
+                Err(e) if e.kind() != io::ErrorKind::NotConnected => return Err(e),
+                _ => return Ok(()),
+            }
+        }
+
+        if let Some(xor) = &mut xor {
+            xor.apply(&mut buf[..n]);
+        }
+        writer.write_all(&buf[..n]).await?;
+        total.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+// with a key, each direction is XORed with its own copy of the keystream; XOR is its own
+// inverse, so the peer instance running the same key undoes it without knowing its role
+// a to b counts as up
+pub async fn copy_bidirectional(
+    a: &mut TcpStream,
+    b: &mut TcpStream,
+    buffer_size: usize,
+    obfuscation_key: Option<&[u8]>,
+    transferred: &Transferred,
+) -> io::Result<()> {
+    let (mut a_read, mut a_write) = a.split();
+    let (mut b_read, mut b_write) = b.split();
+    let xor = obfuscation_key.map(XorStream::new);
+
+    tokio::try_join!(
+        copy_half(
+            &mut a_read,
+            &mut b_write,
+            buffer_size,
+            xor.clone(),
+            &transferred.up
+        ),
+        copy_half(
+            &mut b_read,
+            &mut a_write,
+            buffer_size,
+            xor,
+            &transferred.down
+        )
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // the two ends of one loopback connection
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn half_close_keeps_the_other_direction_flowing() {
+        let (mut client, mut proxy_client) = socket_pair().await;
+        let (mut proxy_server, mut server) = socket_pair().await;
+        let relay = tokio::spawn(async move {
+            let transferred = Transferred::default();
+            copy_bidirectional(
+                &mut proxy_client,
+                &mut proxy_server,
+                1024,
+                None,
+                &transferred,
+            )
+            .await
+            .map(|()| transferred.get())
+        });
+
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        // the FIN reaches the server while the client can still receive
+        let mut request = Vec::new();
+        server.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"hello");
+
+        server
+            .write_all(b"world, after the half-close")
+            .await
+            .unwrap();
+        server.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"world, after the half-close");
+
+        assert_eq!(relay.await.unwrap().unwrap(), (5, 27));
+    }
+}
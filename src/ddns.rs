@@ -1,11 +1,42 @@
 mod cloudflare;
+mod error;
 
-use std::sync::LazyLock;
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use tokio::sync::OnceCell;
+use chrono::Local;
+use tokio::sync::{Mutex, MutexGuard, OnceCell, RwLock};
+use tracing::{Instrument, field::Empty};
 
-use crate::config::{DDNSConfig, DDNSProvider};
+pub use error::DdnsError;
+
+use crate::{
+    LAST_DDNS_UPDATE, WAN_ADDR, WAN_ADDR6,
+    config::{DDNSConfig, DDNSProvider, GeneralConfig},
+    metrics::METRICS,
+    state,
+};
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(15 * 60);
+
+// the AAAA content last written, so unchanged IPv6 addresses cost no API calls
+static PUBLISHED_V6: RwLock<Option<Ipv6Addr>> = RwLock::const_new(None);
+
+// held for each attempt at changing records, never across a retry delay, so shutdown can
+// wait for the attempt instead of cutting it in half
+static CHANGING: Mutex<()> = Mutex::const_new(());
+
+// nothing reaches the zone, so nothing may be remembered as published either
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
 
 pub static PROVIDER: OnceCell<Box<dyn DynamicDns + Send + Sync>> = OnceCell::const_new();
 pub static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
@@ -18,15 +49,217 @@ pub static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
 
 #[async_trait]
 pub trait DynamicDns {
-    async fn update_srv(&self, host: &str, port: u16) -> anyhow::Result<()>;
+    async fn update_srv(&self, host: &str, port: u16) -> Result<(), DdnsError>;
+    // None removes the AAAA record once the IPv6 address is gone
+    async fn update_aaaa(&self, ip: Option<Ipv6Addr>) -> Result<(), DdnsError>;
+    // replaces every A record of the name so that exactly ips remain
+    async fn update_a_records(&self, ips: &[IpAddr]) -> Result<(), DdnsError>;
+    // re-reads the records to confirm they carry host and port
+    async fn verify(&self, host: &str, port: u16) -> Result<bool, DdnsError>;
+    async fn delete_record(&self, rectype: &str, name: &str) -> Result<(), DdnsError>;
+    // checks the new token against the API before replacing the current one,
+    // returns false when the token is unchanged
+    async fn update_token(&self, token: String) -> Result<bool, DdnsError>;
+    // the records update_srv maintains, as (type, name)
+    fn records(&self) -> Vec<(&'static str, String)>;
 }
 
-pub fn init(config: DDNSConfig) -> anyhow::Result<()> {
+pub fn provider() -> Option<&'static (dyn DynamicDns + Send + Sync)> {
+    PROVIDER.get().map(|provider| provider.as_ref())
+}
+
+pub fn init(config: DDNSConfig, instance_name: Option<&str>, dry_run: bool) -> anyhow::Result<()> {
     let provider = match config.provider {
-        DDNSProvider::Cloudflare => cloudflare::Provider::new(config),
+        DDNSProvider::Cloudflare => cloudflare::Provider::new(config, instance_name, dry_run),
     };
+    if dry_run {
+        tracing::warn!("Dry run: DNS changes will only be logged, no records are written");
+        DRY_RUN.store(true, Ordering::Relaxed);
+    }
 
     let _ = PROVIDER.set(Box::new(provider));
 
     Ok(())
 }
+
+// the persisted address, dropped when the zone was edited behind our back so the first
+// check republishes instead of skipping
+pub async fn last_published(config: &GeneralConfig) -> Option<SocketAddr> {
+    let addr = state::load(&config.state_file)?;
+    tracing::info!("Loaded last known WAN address: {}", addr);
+
+    let Some(provider) = provider().filter(|_| config.verify_state) else {
+        return Some(addr);
+    };
+    match provider.verify(&addr.ip().to_string(), addr.port()).await {
+        Ok(true) => Some(addr),
+        Ok(false) => {
+            tracing::info!(
+                "DNS records no longer carry {}, republishing on the first check",
+                addr
+            );
+            None
+        }
+        Err(e) => {
+            tracing::warn!(
+                "Failed to check the DNS records for {}: {}, republishing on the first check",
+                addr,
+                e
+            );
+            None
+        }
+    }
+}
+
+// true once the records are confirmed to carry addr (or there is nothing to publish to),
+// callers only treat addr as the last published address in that case
+pub async fn publish(state_file: &Path, addr: SocketAddr, last_addr: Option<SocketAddr>) -> bool {
+    {
+        let mut wa = WAN_ADDR.get().unwrap().write().await;
+        *wa = addr;
+    }
+
+    if last_addr == Some(addr) {
+        tracing::info!("Public addr unchanged since last update, skipping DDNS update.");
+        return true;
+    }
+
+    let Some(provider) = provider() else {
+        tracing::warn!(
+            "No DDNS provider available, public addr {} detected but not published.",
+            addr
+        );
+        return true;
+    };
+
+    let span = tracing::info_span!("ddns_update", address = %addr, result = Empty);
+    let updated = update(provider, state_file, addr)
+        .instrument(span.clone())
+        .await;
+    span.record("result", if updated { "updated" } else { "failed" });
+    updated
+}
+
+async fn update(
+    provider: &(dyn DynamicDns + Send + Sync),
+    state_file: &Path,
+    addr: SocketAddr,
+) -> bool {
+    let host = addr.ip().to_string();
+    loop {
+        let res = {
+            let _changing = CHANGING.lock().await;
+            match provider.update_srv(&host, addr.port()).await {
+                Ok(()) => provider.verify(&host, addr.port()).await,
+                Err(e) => Err(e),
+            }
+        };
+        match res {
+            Ok(false) => {
+                METRICS.ddns_failures.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "DNS records do not reflect {} after the update, retrying in {}s...",
+                    addr,
+                    RETRY_DELAY.as_secs()
+                );
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+            Ok(true) => {
+                METRICS.ddns_updates.fetch_add(1, Ordering::Relaxed);
+                *LAST_DDNS_UPDATE.write().await = Some(Local::now());
+                if !DRY_RUN.load(Ordering::Relaxed)
+                    && let Err(e) = state::save(state_file, addr)
+                {
+                    tracing::warn!("Failed to persist WAN address: {}", e);
+                }
+                return true;
+            }
+            Err(e) if e.is_retryable() => {
+                METRICS.ddns_failures.fetch_add(1, Ordering::Relaxed);
+                // honour the provider's backoff hint, within reason
+                let delay = e
+                    .retry_after()
+                    .map_or(RETRY_DELAY, |d| d.clamp(RETRY_DELAY, MAX_RETRY_AFTER));
+                tracing::error!(
+                    "An error occurred while updating the SRV record: {}, retrying in {}s...",
+                    e,
+                    delay.as_secs()
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                METRICS.ddns_failures.fetch_add(1, Ordering::Relaxed);
+                tracing::error!(
+                    "Failed to update the SRV record: {}. Not retrying, check the DDNS configuration.",
+                    e
+                );
+                return false;
+            }
+        }
+    }
+}
+
+pub async fn publish_v6(ip: Option<IpAddr>) {
+    let ip = ip.and_then(|ip| match ip {
+        IpAddr::V6(ip) => Some(ip),
+        IpAddr::V4(_) => None,
+    });
+    *WAN_ADDR6.write().await = ip;
+
+    let Some(provider) = provider() else {
+        return;
+    };
+    let mut published = PUBLISHED_V6.write().await;
+    if *published == ip {
+        return;
+    }
+
+    let _changing = CHANGING.lock().await;
+    match provider.update_aaaa(ip).await {
+        Ok(()) => *published = ip,
+        // left unchanged so the next address check tries again
+        Err(e) => tracing::error!("Failed to update the AAAA record: {}", e),
+    }
+}
+
+// waits for a record change in flight; none starts while the guard is held
+pub async fn hold_updates() -> MutexGuard<'static, ()> {
+    CHANGING.lock().await
+}
+
+pub async fn remove_records() {
+    let Some(provider) = provider() else {
+        return;
+    };
+
+    if PUBLISHED_V6.read().await.is_some()
+        && let Err(e) = provider.update_aaaa(None).await
+    {
+        tracing::warn!("Failed to delete AAAA record: {}", e);
+    }
+
+    for (rectype, name) in provider.records() {
+        match tokio::time::timeout(
+            Duration::from_secs(10),
+            provider.delete_record(rectype, &name),
+        )
+        .await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!("Failed to delete {} record {}: {}", rectype, name, e),
+            Err(_) => tracing::warn!("Timed out deleting {} record {}", rectype, name),
+        }
+    }
+}
+
+pub async fn update_token(token: String) {
+    let Some(provider) = provider() else {
+        return;
+    };
+
+    match provider.update_token(token).await {
+        Ok(true) => tracing::info!("DDNS token replaced, later updates use the new token"),
+        Ok(false) => {}
+        Err(e) => tracing::error!("New DDNS token rejected, keeping the current one: {}", e),
+    }
+}
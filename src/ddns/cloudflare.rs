@@ -1,71 +1,415 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::RwLock,
+};
+
 use async_trait::async_trait;
 use serde_json::{Value, json};
 
 use crate::{
-    config::DDNSConfig,
-    ddns::{DynamicDns, HTTP_CLIENT},
+    config::{DDNSConfig, RecordMode},
+    ddns::{DdnsError, DynamicDns, HTTP_CLIENT},
 };
 
+async fn send(req: reqwest::RequestBuilder) -> Result<reqwest::Response, DdnsError> {
+    let resp = req.send().await?;
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+
+    Err(DdnsError::from_response(resp).await)
+}
+
+const SEARCH_PAGE_SIZE: u32 = 100;
+const MANAGED_COMMENT: &str = "Managed by BedrockHole";
+
+fn qualify(sub_domain: &str, domain: &str) -> String {
+    if sub_domain.is_empty() || sub_domain == "@" {
+        domain.to_string()
+    } else {
+        format!("{}.{}", sub_domain, domain)
+    }
+}
+
+// walks the result pages fetch returns; the name filter alone also matches other record
+// types sharing the name
+async fn collect_records<F, Fut>(
+    rectype: &str,
+    full_name: &str,
+    mut fetch: F,
+) -> Result<Vec<Value>, DdnsError>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = Result<Value, DdnsError>>,
+{
+    let mut records = Vec::new();
+    let mut page = 1;
+    loop {
+        let resp = fetch(page).await?;
+        records.extend(
+            resp["result"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|record| {
+                    record["type"].as_str() == Some(rectype)
+                        && record["name"]
+                            .as_str()
+                            .is_some_and(|name| name.eq_ignore_ascii_case(full_name))
+                })
+                .cloned(),
+        );
+
+        let total_pages = resp["result_info"]["total_pages"].as_u64().unwrap_or(1);
+        if page >= total_pages {
+            return Ok(records);
+        }
+        page += 1;
+    }
+}
+
 pub struct Provider {
-    token: String,
+    // swapped in place by a SIGHUP reload, so requests read it fresh each time
+    token: RwLock<String>,
     domain: String,
     sub_domain: String,
+    ttl: u32,
+    manage_a: bool,
+    manage_srv: bool,
+    additional_addresses: Vec<Ipv4Addr>,
+    // set in CNAME mode, where the name aliases this target instead of carrying addresses
+    cname_target: Option<String>,
+    // template with {ip} and {port}, and the fully qualified name it is published under
+    txt: Option<(String, String)>,
+    comment: String,
+    tags: Vec<String>,
+    // take over records another tool or instance has marked as its own
+    force: bool,
+    // reads still go to the API so the log shows exactly what would change
+    dry_run: bool,
 }
 
 impl Provider {
-    pub fn new(config: DDNSConfig) -> Self {
+    pub fn new(config: DDNSConfig, instance_name: Option<&str>, dry_run: bool) -> Self {
+        let txt = config.txt_content.map(|content| {
+            let name = config.txt_name.as_deref().unwrap_or(&config.sub_domain);
+            (content, qualify(name, &config.domain))
+        });
         Self {
-            token: config.token,
+            token: RwLock::new(config.token),
             domain: config.domain,
             sub_domain: config.sub_domain,
+            ttl: config.ttl,
+            manage_a: config.manage_a,
+            manage_srv: config.manage_srv,
+            additional_addresses: config.additional_addresses,
+            cname_target: config
+                .cname_target
+                .filter(|_| config.record_mode == RecordMode::Cname)
+                .map(|target| target.trim().trim_end_matches('.').to_string()),
+            txt,
+            comment: match instance_name {
+                Some(name) => format!("{} ({})", MANAGED_COMMENT, name),
+                None => MANAGED_COMMENT.to_string(),
+            },
+            tags: config.tags,
+            force: config.force,
+            dry_run,
         }
     }
 
-    async fn fetch_zone_id(&self) -> anyhow::Result<String> {
+    fn token(&self) -> String {
+        self.token.read().unwrap().clone()
+    }
+
+    async fn fetch_zone_id(&self) -> Result<String, DdnsError> {
+        self.fetch_zone_id_with(&self.token()).await
+    }
+
+    async fn fetch_zone_id_with(&self, token: &str) -> Result<String, DdnsError> {
         tracing::debug!(domain = %self.domain, "Fetching Cloudflare Zone ID");
 
         let url = format!(
             "https://api.cloudflare.com/client/v4/zones?name={}",
             self.domain
         );
-        let resp: Value = HTTP_CLIENT
-            .get(url)
-            .bearer_auth(&self.token)
-            .send()
+        let resp: Value = send(HTTP_CLIENT.get(url).bearer_auth(token))
             .await?
             .json()
             .await?;
 
         resp["result"]
             .as_array()
-            .and_then(|list| list.get(0))
+            .and_then(|list| list.first())
             .and_then(|zone| zone["id"].as_str())
             .map(|id| id.to_string())
-            .ok_or_else(|| anyhow::anyhow!("Zone ID not found for domain: {}", self.domain))
+            .ok_or_else(|| DdnsError::NotFound(format!("zone for domain {}", self.domain)))
     }
 
-    async fn search_record_id(
+    async fn list_records(
         &self,
         zone_id: &str,
+        rectype: &str,
         full_name: &str,
-    ) -> anyhow::Result<Option<String>> {
-        let url = format!(
-            "https://api.cloudflare.com/client/v4/zones/{}/dns_records?name={}",
-            zone_id, full_name
-        );
-        let resp: Value = HTTP_CLIENT
-            .get(url)
-            .bearer_auth(&self.token)
-            .send()
+    ) -> Result<Vec<Value>, DdnsError> {
+        collect_records(rectype, full_name, |page| async move {
+            let url = format!(
+                "https://api.cloudflare.com/client/v4/zones/{}/dns_records?type={}&name={}&page={}&per_page={}",
+                zone_id, rectype, full_name, page, SEARCH_PAGE_SIZE
+            );
+            Ok(send(HTTP_CLIENT.get(url).bearer_auth(self.token()))
+                .await?
+                .json()
+                .await?)
+        })
+        .await
+    }
+
+    async fn search_record(
+        &self,
+        zone_id: &str,
+        rectype: &str,
+        full_name: &str,
+    ) -> Result<Option<Value>, DdnsError> {
+        Ok(self
+            .list_records(zone_id, rectype, full_name)
             .await?
-            .json()
+            .into_iter()
+            .next())
+    }
+
+    // makes the A records at full_name exactly match ips, one record per address
+    async fn sync_a_records(
+        &self,
+        zone_id: &str,
+        full_name: &str,
+        ips: &[IpAddr],
+    ) -> Result<(), DdnsError> {
+        let wanted: Vec<String> = ips
+            .iter()
+            .filter(|ip| ip.is_ipv4())
+            .map(|ip| ip.to_string())
+            .collect();
+        let existing = self.list_records(zone_id, "A", full_name).await?;
+
+        for record in &existing {
+            let content = record["content"].as_str().unwrap_or_default();
+            let keep = wanted.iter().any(|ip| ip == content);
+            if keep && self.ttl_matches(record) {
+                continue;
+            }
+            let Some(id) = record["id"].as_str() else {
+                continue;
+            };
+            self.check_owner(record, "A", full_name)?;
+            let url = format!(
+                "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
+                zone_id, id
+            );
+            if keep {
+                if self.dry_run {
+                    tracing::info!(name = %full_name, content = %content, ttl = self.ttl, "Dry run, would update Cloudflare A record TTL");
+                    continue;
+                }
+                let mut payload = json!({ "ttl": self.ttl });
+                self.add_comment(&mut payload);
+                send(
+                    HTTP_CLIENT
+                        .patch(url)
+                        .bearer_auth(self.token())
+                        .json(&payload),
+                )
+                .await?;
+                tracing::info!(name = %full_name, content = %content, ttl = self.ttl, "Cloudflare A record TTL updated");
+                continue;
+            }
+            if self.dry_run {
+                tracing::info!(name = %full_name, content = %content, "Dry run, would remove Cloudflare A record");
+                continue;
+            }
+            send(HTTP_CLIENT.delete(url).bearer_auth(self.token())).await?;
+            tracing::info!(name = %full_name, content = %content, "Cloudflare A record removed");
+        }
+
+        for ip in &wanted {
+            if existing
+                .iter()
+                .any(|record| record["content"].as_str() == Some(ip))
+            {
+                continue;
+            }
+            if self.dry_run {
+                tracing::info!(name = %full_name, content = %ip, "Dry run, would add Cloudflare A record");
+                continue;
+            }
+            let url = format!(
+                "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
+                zone_id
+            );
+            let mut payload = json!({
+                "type": "A",
+                "name": full_name,
+                "content": ip,
+                "proxied": false,
+                "ttl": self.ttl,
+            });
+            self.add_comment(&mut payload);
+            send(
+                HTTP_CLIENT
+                    .post(url)
+                    .bearer_auth(self.token())
+                    .json(&payload),
+            )
             .await?;
+            tracing::info!(name = %full_name, content = %ip, "Cloudflare A record added");
+        }
 
-        Ok(resp["result"]
-            .as_array()
-            .and_then(|list| list.get(0))
-            .and_then(|rec| rec["id"].as_str())
-            .map(|id| id.to_string()))
+        Ok(())
+    }
+
+    fn a_record_ips(&self, host: &str) -> Vec<IpAddr> {
+        host.parse()
+            .into_iter()
+            .chain(self.additional_addresses.iter().map(|&ip| IpAddr::V4(ip)))
+            .collect()
+    }
+
+    // SRV targets must not be aliases (RFC 2782), so in CNAME mode they skip the CNAME
+    fn srv_target(&self) -> String {
+        self.cname_target
+            .clone()
+            .unwrap_or_else(|| self.a_record_name())
+    }
+
+    // a CNAME cannot share its name with address records, not even at the flattened apex
+    async fn upsert_cname(&self, zone_id: &str, name: &str, target: &str) -> Result<(), DdnsError> {
+        for rectype in ["A", "AAAA"] {
+            for record in self.list_records(zone_id, rectype, name).await? {
+                self.remove(zone_id, rectype, name, &record).await?;
+            }
+        }
+        self.upsert_record(zone_id, "CNAME", name, target, None)
+            .await
+    }
+
+    async fn remove(
+        &self,
+        zone_id: &str,
+        rectype: &str,
+        name: &str,
+        record: &Value,
+    ) -> Result<(), DdnsError> {
+        let id = record["id"]
+            .as_str()
+            .ok_or_else(|| DdnsError::Other(format!("Record {} has no id", name)))?;
+        self.check_owner(record, rectype, name)?;
+
+        if self.dry_run {
+            tracing::info!(rectype = %rectype, name = %name, "Dry run, would delete Cloudflare record");
+            return Ok(());
+        }
+
+        let url = format!(
+            "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
+            zone_id, id
+        );
+        send(HTTP_CLIENT.delete(url).bearer_auth(self.token())).await?;
+        tracing::info!(rectype = %rectype, name = %name, "Cloudflare record deleted");
+        Ok(())
+    }
+
+    fn a_record_name(&self) -> String {
+        qualify(&self.sub_domain, &self.domain)
+    }
+
+    fn srv_data(&self, target: &str, port: u16) -> Value {
+        // the owner name must be fully qualified, an empty sub_domain is not a valid name
+        json!({
+            "service": "_minecraft",
+            "proto": "_tcp",
+            "name": self.a_record_name(),
+            "priority": 10,
+            "weight": 0,
+            "port": port,
+            "target": target,
+        })
+    }
+
+    fn txt_record(&self, host: &str, port: u16) -> Option<(String, &str)> {
+        self.txt.as_ref().map(|(template, name)| {
+            let content = template
+                .replace("{ip}", host)
+                .replace("{port}", &port.to_string());
+            (content, name.as_str())
+        })
+    }
+
+    fn add_comment(&self, payload: &mut Value) {
+        payload["comment"] = json!(self.comment);
+        if !self.tags.is_empty() {
+            payload["tags"] = json!(self.tags);
+        }
+    }
+
+    fn comment_matches(&self, record: &Value) -> bool {
+        record["comment"].as_str() == Some(self.comment.as_str())
+    }
+
+    // records without a comment predate the marker or were made by hand without one,
+    // anything else carrying a comment belongs to someone else
+    fn check_owner(&self, record: &Value, rectype: &str, name: &str) -> Result<(), DdnsError> {
+        let Some(comment) = record["comment"].as_str().filter(|c| !c.is_empty()) else {
+            return Ok(());
+        };
+        if comment == self.comment {
+            return Ok(());
+        }
+        if self.force {
+            tracing::warn!(
+                rectype = %rectype,
+                name = %name,
+                comment = %comment,
+                "Taking over a Cloudflare record managed by something else, ddns.force is set"
+            );
+            return Ok(());
+        }
+        Err(DdnsError::Other(format!(
+            "{} record {} is marked {:?}, not {:?}; refusing to modify it without ddns.force",
+            rectype, name, comment, self.comment
+        )))
+    }
+
+    // a changed ddns.ttl has to be written out even when the content is still right
+    fn ttl_matches(&self, record: &Value) -> bool {
+        record["ttl"].as_u64() == Some(self.ttl as u64)
+    }
+
+    fn record_matches(
+        &self,
+        record: &Value,
+        rectype: &str,
+        content: &str,
+        port: Option<u16>,
+    ) -> bool {
+        if record["type"].as_str() != Some(rectype) || !self.ttl_matches(record) {
+            return false;
+        }
+
+        match rectype {
+            "A" | "AAAA" => record["content"].as_str() == Some(content),
+            "CNAME" => record["content"]
+                .as_str()
+                .is_some_and(|target| target.eq_ignore_ascii_case(content)),
+            // the API may hand the content back wrapped in quotes
+            "TXT" => record["content"]
+                .as_str()
+                .is_some_and(|text| text.trim_matches('"') == content),
+            "SRV" => {
+                record["data"]["target"].as_str() == Some(content)
+                    && record["data"]["port"].as_u64() == Some(port.unwrap_or(0) as u64)
+            }
+            _ => false,
+        }
     }
 
     async fn upsert_record(
@@ -75,32 +419,51 @@ impl Provider {
         full_name: &str,
         content: &str,
         port: Option<u16>,
-    ) -> anyhow::Result<()> {
-        let record_id = self.search_record_id(zone_id, full_name).await?;
+    ) -> Result<(), DdnsError> {
+        let record = self.search_record(zone_id, rectype, full_name).await?;
+        if let Some(record) = &record {
+            self.check_owner(record, rectype, full_name)?;
+        }
+
+        if let Some(record) = &record
+            && self.record_matches(record, rectype, content, port)
+            && self.comment_matches(record)
+        {
+            tracing::info!(
+                rectype = %rectype,
+                name = %full_name,
+                content = %content,
+                "Cloudflare record already up to date, skipping"
+            );
+            return Ok(());
+        }
+
+        let record_id = record
+            .as_ref()
+            .and_then(|rec| rec["id"].as_str())
+            .map(|id| id.to_string());
 
         let mut payload = json!({
             "type": rectype,
             "name": full_name,
             "proxied": false,
-            "ttl": 60,
+            "ttl": self.ttl,
         });
+        self.add_comment(&mut payload);
 
         match rectype {
-            "A" => {
+            "A" | "AAAA" | "CNAME" | "TXT" => {
                 payload["content"] = json!(content);
             }
             "SRV" => {
-                payload["data"] = json!({
-                    "service": "_minecraft",
-                    "proto": "_tcp",
-                    "name": &self.sub_domain,
-                    "priority": 10,
-                    "weight": 0,
-                    "port": port.unwrap_or(0),
-                    "target": content,
-                });
+                payload["data"] = self.srv_data(content, port.unwrap_or(0));
+            }
+            _ => {
+                return Err(DdnsError::Other(format!(
+                    "Unsupported record type: {}",
+                    rectype
+                )));
             }
-            _ => anyhow::bail!("Unsupported record type: {}", rectype),
         }
 
         let (method, url) = match &record_id {
@@ -120,9 +483,21 @@ impl Provider {
             ),
         };
 
+        if self.dry_run {
+            tracing::info!(
+                action = %method,
+                rectype = %rectype,
+                name = %full_name,
+                content = %content,
+                port = ?port,
+                "Dry run, would write Cloudflare record"
+            );
+            return Ok(());
+        }
+
         let resp = HTTP_CLIENT
             .request(method.clone(), url)
-            .bearer_auth(&self.token)
+            .bearer_auth(self.token())
             .json(&payload)
             .send()
             .await?;
@@ -137,22 +512,20 @@ impl Provider {
             );
             Ok(())
         } else {
-            let status = resp.status();
-            let err_text = resp.text().await?;
+            let err = DdnsError::from_response(resp).await;
             tracing::error!(
-                status = %status,
-                error = %err_text,
+                error = %err,
                 name = %full_name,
                 "Cloudflare API request failed"
             );
-            anyhow::bail!("Cloudflare API error ({}): {}", status, err_text)
+            Err(err)
         }
     }
 }
 
 #[async_trait]
 impl DynamicDns for Provider {
-    async fn update_srv(&self, host: &str, port: u16) -> anyhow::Result<()> {
+    async fn update_srv(&self, host: &str, port: u16) -> Result<(), DdnsError> {
         tracing::info!(
             domain = %self.domain,
             sub_domain = %self.sub_domain,
@@ -161,19 +534,254 @@ impl DynamicDns for Provider {
 
         let zone_id = self.fetch_zone_id().await?;
 
-        let a_record_name = if self.sub_domain.is_empty() || self.sub_domain == "@" {
-            self.domain.clone()
-        } else {
-            format!("{}.{}", self.sub_domain, self.domain)
+        let a_record_name = self.a_record_name();
+
+        if let Some(target) = self.cname_target.as_deref().filter(|_| self.manage_a) {
+            self.upsert_cname(&zone_id, &a_record_name, target).await?;
+        } else if self.manage_a && !self.additional_addresses.is_empty() {
+            self.update_a_records(&self.a_record_ips(host)).await?;
+        } else if self.manage_a {
+            self.upsert_record(&zone_id, "A", &a_record_name, host, None)
+                .await?;
+        }
+
+        if self.manage_srv {
+            let srv_name = format!("_minecraft._tcp.{}", a_record_name);
+            self.upsert_record(&zone_id, "SRV", &srv_name, &self.srv_target(), Some(port))
+                .await?;
+        }
+
+        if let Some((content, name)) = self.txt_record(host, port) {
+            self.upsert_record(&zone_id, "TXT", name, &content, None)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn update_aaaa(&self, ip: Option<Ipv6Addr>) -> Result<(), DdnsError> {
+        // the CNAME target answers AAAA queries for the name
+        if !self.manage_a || self.cname_target.is_some() {
+            return Ok(());
+        }
+        let name = self.a_record_name();
+        match ip {
+            Some(ip) => {
+                let zone_id = self.fetch_zone_id().await?;
+                self.upsert_record(&zone_id, "AAAA", &name, &ip.to_string(), None)
+                    .await
+            }
+            None => self.delete_record("AAAA", &name).await,
+        }
+    }
+
+    async fn update_a_records(&self, ips: &[IpAddr]) -> Result<(), DdnsError> {
+        let zone_id = self.fetch_zone_id().await?;
+        self.sync_a_records(&zone_id, &self.a_record_name(), ips)
+            .await
+    }
+
+    async fn verify(&self, host: &str, port: u16) -> Result<bool, DdnsError> {
+        // nothing was written, so the live records cannot confirm anything
+        if self.dry_run {
+            return Ok(true);
+        }
+        let zone_id = self.fetch_zone_id().await?;
+        let a_record_name = self.a_record_name();
+        let srv_name = format!("_minecraft._tcp.{}", a_record_name);
+
+        if let Some(target) = self.cname_target.as_deref().filter(|_| self.manage_a) {
+            let cname = self
+                .search_record(&zone_id, "CNAME", &a_record_name)
+                .await?;
+            if !cname.is_some_and(|r| self.record_matches(&r, "CNAME", target, None)) {
+                return Ok(false);
+            }
+        } else if self.manage_a && self.additional_addresses.is_empty() {
+            let a = self.search_record(&zone_id, "A", &a_record_name).await?;
+            if !a.is_some_and(|r| self.record_matches(&r, "A", host, None)) {
+                return Ok(false);
+            }
+        } else if self.manage_a {
+            let mut wanted: Vec<String> = self
+                .a_record_ips(host)
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect();
+            let mut live: Vec<String> = self
+                .list_records(&zone_id, "A", &a_record_name)
+                .await?
+                .iter()
+                .filter(|r| self.ttl_matches(r))
+                .filter_map(|r| r["content"].as_str().map(str::to_string))
+                .collect();
+            wanted.sort();
+            wanted.dedup();
+            live.sort();
+            if wanted != live {
+                return Ok(false);
+            }
+        }
+        if self.manage_srv {
+            let srv = self.search_record(&zone_id, "SRV", &srv_name).await?;
+            if !srv.is_some_and(|r| self.record_matches(&r, "SRV", &self.srv_target(), Some(port)))
+            {
+                return Ok(false);
+            }
+        }
+        if let Some((content, name)) = self.txt_record(host, port) {
+            let txt = self.search_record(&zone_id, "TXT", name).await?;
+            if !txt.is_some_and(|r| self.record_matches(&r, "TXT", &content, None)) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn delete_record(&self, rectype: &str, name: &str) -> Result<(), DdnsError> {
+        let zone_id = self.fetch_zone_id().await?;
+        let Some(record) = self.search_record(&zone_id, rectype, name).await? else {
+            tracing::info!(rectype = %rectype, name = %name, "Cloudflare record already absent");
+            return Ok(());
         };
+        self.remove(&zone_id, rectype, name, &record).await
+    }
 
-        self.upsert_record(&zone_id, "A", &a_record_name, host, None)
-            .await?;
+    async fn update_token(&self, token: String) -> Result<bool, DdnsError> {
+        if *self.token.read().unwrap() == token {
+            return Ok(false);
+        }
+
+        // a token that cannot see the zone would break every later update
+        self.fetch_zone_id_with(&token).await?;
+        *self.token.write().unwrap() = token;
+
+        Ok(true)
+    }
 
+    fn records(&self) -> Vec<(&'static str, String)> {
+        let a_record_name = self.a_record_name();
         let srv_name = format!("_minecraft._tcp.{}", a_record_name);
-        self.upsert_record(&zone_id, "SRV", &srv_name, &a_record_name, Some(port))
-            .await?;
+        let mut records = Vec::new();
+        if self.manage_a {
+            let rectype = if self.cname_target.is_some() {
+                "CNAME"
+            } else {
+                "A"
+            };
+            records.push((rectype, a_record_name));
+        }
+        if self.manage_srv {
+            records.push(("SRV", srv_name));
+        }
+        if let Some((_, name)) = &self.txt {
+            records.push(("TXT", name.clone()));
+        }
+        records
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(rectype: &str, name: &str, content: &str) -> Value {
+        json!({ "id": format!("{}-{}", rectype, content), "type": rectype, "name": name, "content": content })
+    }
+
+    fn page(records: Vec<Value>, total_pages: u64) -> Value {
+        json!({ "result": records, "result_info": { "total_pages": total_pages } })
+    }
+
+    async fn collect_pages(pages: Vec<Value>, rectype: &str, name: &str) -> (Vec<Value>, Vec<u64>) {
+        let mut fetched = Vec::new();
+        let records = collect_records(rectype, name, |n| {
+            fetched.push(n);
+            let resp = pages[n as usize - 1].clone();
+            async move { Ok(resp) }
+        })
+        .await
+        .unwrap();
+        (records, fetched)
+    }
+
+    fn provider(sub_domain: &str) -> Provider {
+        let config = DDNSConfig {
+            domain: "example.com".to_string(),
+            sub_domain: sub_domain.to_string(),
+            ..DDNSConfig::default()
+        };
+        Provider::new(config, None, false)
+    }
+
+    #[test]
+    fn apex_names_qualify_to_the_domain() {
+        for sub_domain in ["@", ""] {
+            let provider = provider(sub_domain);
+            assert_eq!(provider.a_record_name(), "example.com");
+
+            let data = provider.srv_data(&provider.srv_target(), 41234);
+            assert_eq!(data["name"], "example.com");
+            assert_eq!(data["target"], "example.com");
+            assert_eq!(data["port"], 41234);
+        }
+    }
+
+    #[test]
+    fn subdomain_names_qualify_under_the_domain() {
+        let provider = provider("mc");
+        assert_eq!(provider.a_record_name(), "mc.example.com");
+
+        let data = provider.srv_data(&provider.srv_target(), 41234);
+        assert_eq!(
+            data,
+            json!({
+                "service": "_minecraft",
+                "proto": "_tcp",
+                "name": "mc.example.com",
+                "priority": 10,
+                "weight": 0,
+                "port": 41234,
+                "target": "mc.example.com",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn records_sharing_a_name_are_told_apart_by_type() {
+        let pages = vec![page(
+            vec![
+                record("AAAA", "mc.example.com", "2001:db8::1"),
+                record("A", "mc.example.com", "203.0.113.7"),
+                record("SRV", "mc.example.com", "10 0 25565 mc.example.com"),
+                record("TXT", "mc.example.com", "v=mc1"),
+                record("A", "MC.example.com", "203.0.113.8"),
+                record("A", "other.example.com", "203.0.113.9"),
+            ],
+            1,
+        )];
+
+        let (a, _) = collect_pages(pages.clone(), "A", "mc.example.com").await;
+        let contents: Vec<_> = a.iter().map(|r| r["content"].as_str().unwrap()).collect();
+        assert_eq!(contents, ["203.0.113.7", "203.0.113.8"]);
+
+        let (srv, _) = collect_pages(pages, "SRV", "mc.example.com").await;
+        assert_eq!(srv.len(), 1);
+        assert_eq!(srv[0]["id"], "SRV-10 0 25565 mc.example.com");
+    }
+
+    #[tokio::test]
+    async fn a_match_on_a_later_page_is_found() {
+        let name = "_minecraft._tcp.mc.example.com";
+        let pages = vec![
+            page(vec![record("TXT", name, "unrelated")], 2),
+            page(vec![record("SRV", name, "10 0 41234 mc.example.com")], 2),
+        ];
+
+        let (srv, fetched) = collect_pages(pages, "SRV", name).await;
+        assert_eq!(fetched, [1, 2]);
+        assert_eq!(srv.len(), 1);
+        assert_eq!(srv[0]["content"], "10 0 41234 mc.example.com");
     }
 }
@@ -0,0 +1,103 @@
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::{StatusCode, header::HeaderMap};
+
+#[derive(Debug)]
+pub enum DdnsError {
+    Auth(String),
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    NotFound(String),
+    Transient(String),
+    Other(String),
+}
+
+impl DdnsError {
+    pub fn from_status(status: StatusCode, body: String) -> Self {
+        let msg = format!("{}: {}", status, body);
+        match status.as_u16() {
+            401 | 403 => DdnsError::Auth(msg),
+            404 => DdnsError::NotFound(msg),
+            429 => DdnsError::RateLimited {
+                message: msg,
+                retry_after: None,
+            },
+            408 | 500..=599 => DdnsError::Transient(msg),
+            _ => DdnsError::Other(msg),
+        }
+    }
+
+    pub async fn from_response(resp: reqwest::Response) -> Self {
+        let status = resp.status();
+        let retry_after = retry_after(resp.headers());
+        let body = resp.text().await.unwrap_or_default();
+        match Self::from_status(status, body) {
+            DdnsError::RateLimited { message, .. } => DdnsError::RateLimited {
+                message,
+                retry_after,
+            },
+            other => other,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            DdnsError::RateLimited { .. } | DdnsError::Transient(_)
+        )
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            DdnsError::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DdnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DdnsError::Auth(e) => write!(f, "authentication failed ({})", e),
+            DdnsError::RateLimited { message, .. } => write!(f, "rate limited ({})", message),
+            DdnsError::NotFound(e) => write!(f, "not found ({})", e),
+            DdnsError::Transient(e) => write!(f, "temporary failure ({})", e),
+            DdnsError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DdnsError {}
+
+// Retry-After is either delta-seconds or an HTTP date; Cloudflare also sends the
+// draft RateLimit-Reset header in seconds
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(value) = headers.get("retry-after").and_then(|v| v.to_str().ok()) {
+        if let Ok(secs) = value.trim().parse::<u64>() {
+            return Some(Duration::from_secs(secs));
+        }
+        if let Ok(date) = DateTime::parse_from_rfc2822(value.trim()) {
+            return (date.with_timezone(&Utc) - Utc::now()).to_std().ok();
+        }
+    }
+
+    headers
+        .get("ratelimit-reset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+impl From<reqwest::Error> for DdnsError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) => DdnsError::from_status(status, e.to_string()),
+            None if e.is_decode() => DdnsError::Other(e.to_string()),
+            // timeouts, refused connections and TLS hiccups
+            None => DdnsError::Transient(e.to_string()),
+        }
+    }
+}
@@ -1,248 +1,1061 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, LazyLock, atomic::Ordering},
+};
 
 use anyhow::anyhow;
+use socket2::SockRef;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional},
-    net::{TcpListener, TcpSocket, TcpStream, lookup_host},
+    io::{AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::{mpsc, watch},
+    task::JoinSet,
+    time::Instant,
 };
+use tracing::{Instrument, Span, field::Empty};
 
 use crate::{
-    WAN_ADDR,
-    config::{ForwardConfig, HAProxyVersion},
+    LISTENER_READY, WAN_ADDR,
+    config::{ForwardConfig, TunnelRole},
+    copy::Transferred,
+    geoip::GeoIp,
+    handshake, heartbeat,
+    metrics::{self, ConnectionGuard, METRICS},
+    mux::{self, Mux, MuxPool, MuxStream},
+    proxy_protocol,
+    ratelimit::{PerIpLimit, TokenBucket},
+    relay::{self, relay},
+    sockopt, tunnel,
+    upstream::{UpstreamPool, UpstreamStream},
 };
 
-async fn forward(
-    mut client_stream: TcpStream,
-    server: SocketAddr,
-    haproxy: bool,
-) -> anyhow::Result<()> {
-    let mut server_stream = TcpStream::connect(server).await?;
+const REJECT_LOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+const PEEK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+const PLAYER_QUEUE: usize = 64;
+const SESSION_LIMIT_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+const ACCEPT_RETRY: std::time::Duration = std::time::Duration::from_millis(100);
+const FD_BACKOFF_MIN: std::time::Duration = std::time::Duration::from_secs(1);
+const FD_BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(10);
 
-    if haproxy {
-        let client_addr = client_stream.peer_addr()?;
-        let server_local_addr = server_stream.local_addr()?;
+// the forward section in effect, replaced by SIGHUP reloads and outliving worker restarts
+static CONFIG: LazyLock<watch::Sender<Option<Arc<ForwardConfig>>>> =
+    LazyLock::new(|| watch::Sender::new(None));
 
-        let header = match (client_addr, server_local_addr) {
-            (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
-                format!(
-                    "PROXY TCP4 {} {} {} {}\r\n",
-                    src.ip(),
-                    dst.ip(),
-                    src.port(),
-                    dst.port()
-                )
-            }
-            (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
-                format!(
-                    "PROXY TCP6 {} {} {} {}\r\n",
-                    src.ip(),
-                    dst.ip(),
-                    src.port(),
-                    dst.port()
-                )
-            }
-            _ => return Err(anyhow::anyhow!("Mismatched IP families for PROXY v1")),
+// hands a reloaded forward section to the worker, which rebinds only if something changed
+pub fn reload(config: ForwardConfig) {
+    let config = Arc::new(config);
+    CONFIG.send_if_modified(|current| {
+        let Some(old) = current else {
+            // forwarding is disabled, nothing to rebind
+            return false;
         };
+        if serde_json::to_value(&**old).ok() == serde_json::to_value(&*config).ok() {
+            return false;
+        }
+        if old.local_port != config.local_port {
+            tracing::warn!(
+                "forward.local_port changed to {}, address discovery keeps mapping {} until a restart",
+                config.local_port,
+                old.local_port
+            );
+        }
+        *current = Some(config.clone());
+        true
+    });
+}
+
+// one span per forwarded session, filled in as the session progresses
+fn session_span(client_addr: SocketAddr) -> Span {
+    tracing::info_span!(
+        "session",
+        client = %client_addr,
+        upstream = Empty,
+        bytes_up = Empty,
+        bytes_down = Empty,
+        result = Empty,
+    )
+}
+
+fn record_transfer(upstream: &str, (up, down): (u64, u64)) {
+    METRICS.bytes_up.fetch_add(up, Ordering::Relaxed);
+    METRICS.bytes_down.fetch_add(down, Ordering::Relaxed);
+    Span::current()
+        .record("upstream", upstream)
+        .record("bytes_up", up)
+        .record("bytes_down", down);
+}
+
+fn record_result(res: &anyhow::Result<()>) {
+    match res {
+        Ok(()) => Span::current().record("result", "ok"),
+        Err(e) => Span::current().record("result", tracing::field::display(e)),
+    };
+}
 
-        server_stream.write_all(header.as_bytes()).await?;
+// the sockets a session is cut through once max_session_duration is up
+struct SessionLimit {
+    after: std::time::Duration,
+    // how long the half-closed session gets to wind down before it is cut off
+    grace: std::time::Duration,
+    sockets: Vec<socket2::Socket>,
+}
+
+impl SessionLimit {
+    fn new<'a>(
+        config: &ForwardConfig,
+        sockets: impl IntoIterator<Item = SockRef<'a>>,
+    ) -> io::Result<Option<Self>> {
+        if config.max_session_duration == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            after: std::time::Duration::from_secs(config.max_session_duration),
+            grace: SESSION_LIMIT_GRACE,
+            sockets: sockets
+                .into_iter()
+                .map(|s| s.try_clone())
+                .collect::<io::Result<_>>()?,
+        }))
     }
+}
+
+// the byte counts are recorded however the session ends, including when it is cut off
+async fn limit_session(
+    limit: Option<SessionLimit>,
+    client_addr: SocketAddr,
+    upstream: &str,
+    transferred: &Transferred,
+    relay: impl Future<Output = io::Result<()>>,
+) -> io::Result<()> {
+    let res = run_limited(limit, client_addr, transferred, relay).await;
+    record_transfer(upstream, transferred.get());
+    res
+}
 
-    tokio::io::copy_bidirectional(&mut client_stream, &mut server_stream).await?;
+async fn run_limited(
+    limit: Option<SessionLimit>,
+    client_addr: SocketAddr,
+    transferred: &Transferred,
+    relay: impl Future<Output = io::Result<()>>,
+) -> io::Result<()> {
+    let Some(limit) = limit else {
+        return relay.await;
+    };
+    tokio::pin!(relay);
+    tokio::select! {
+        res = &mut relay => return res,
+        () = tokio::time::sleep(limit.after) => {}
+    }
+
+    // every copy loop reads EOF and finishes normally
+    for socket in &limit.sockets {
+        let _ = socket.shutdown(std::net::Shutdown::Read);
+    }
+    let res = tokio::time::timeout(limit.grace, &mut relay).await;
+    let (up, down) = transferred.get();
+    match res {
+        Ok(Ok(())) => {
+            tracing::info!(
+                "Session of {} hit the {}s time limit and was closed, {} bytes up, {} bytes down",
+                client_addr,
+                limit.after.as_secs(),
+                up,
+                down
+            );
+            Ok(())
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => {
+            for socket in &limit.sockets {
+                let _ = socket.shutdown(std::net::Shutdown::Both);
+            }
+            tracing::info!(
+                "Session of {} hit the {}s time limit and did not wind down, cut off after {} bytes up, {} bytes down",
+                client_addr,
+                limit.after.as_secs(),
+                up,
+                down
+            );
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "session hit the {}s time limit and did not wind down, cut off",
+                    limit.after.as_secs()
+                ),
+            ))
+        }
+    }
+}
+
+// never leaves a half-initialized upstream session behind
+async fn send_proxy_header<S: AsyncWrite + Unpin>(
+    server_stream: &mut S,
+    client_addr: SocketAddr,
+    local_addr: io::Result<SocketAddr>,
+    config: &ForwardConfig,
+) -> anyhow::Result<()> {
+    let res = async {
+        let header = proxy_protocol::encode(config.haproxy_version, client_addr, local_addr?)?;
+        server_stream.write_all(&header).await?;
+        Ok::<(), anyhow::Error>(())
+    }
+    .await;
+
+    if res.is_err() {
+        let _ = server_stream.shutdown().await;
+    }
+    res
+}
+
+// one stream of a multiplexed tunnel on the home side, dialled like a direct session
+async fn forward_stream(
+    stream: MuxStream,
+    client_addr: SocketAddr,
+    pool: &UpstreamPool,
+    config: &ForwardConfig,
+) -> anyhow::Result<()> {
+    let (server_stream, lease) = pool.connect(config).await?;
+    let mut server_stream = server_stream.into_tcp()?;
+    tracing::info!(
+        "Forwarding {} to upstream {} over the multiplexed tunnel",
+        client_addr,
+        lease.upstream().name()
+    );
+
+    if let Err(e) = sockopt::apply_keepalive(&server_stream, &config.keepalive) {
+        tracing::warn!("Failed to configure TCP keepalive: {}", e);
+    }
+    if config.haproxy_support {
+        let local_addr = server_stream.local_addr();
+        send_proxy_header(&mut server_stream, client_addr, local_addr, config).await?;
+    }
+
+    let limit = SessionLimit::new(config, [SockRef::from(&server_stream)])?;
+    let transferred = Transferred::default();
+    // the home side pumps server bytes into the tunnel, so those count as down
+    limit_session(
+        limit,
+        client_addr,
+        &lease.upstream().name(),
+        &transferred,
+        mux::pump(
+            &mut server_stream,
+            stream,
+            &transferred.down,
+            &transferred.up,
+        ),
+    )
+    .await?;
 
     Ok(())
 }
 
-async fn forward_v2(
-    mut client_stream: TcpStream,
-    server: SocketAddr,
-    haproxy: bool,
+async fn serve_mux(
+    carrier: TcpStream,
+    session: Option<tunnel::Session>,
+    pool: Arc<UpstreamPool>,
+    config: Arc<ForwardConfig>,
 ) -> anyhow::Result<()> {
-    let mut server_stream = TcpStream::connect(server).await?;
+    let carrier_addr = carrier.peer_addr()?;
+    let (accept, mut accepted) = mpsc::channel(16);
+    let _mux = Mux::start(carrier, session, Some(accept), None);
+    tracing::info!("Multiplexed tunnel from {} established", carrier_addr);
+
+    let mut streams = JoinSet::new();
+    loop {
+        tokio::select! {
+            Some(res) = streams.join_next() => {
+                if let Err(e) = res
+                    && e.is_panic()
+                {
+                    tracing::error!("Proxy session panicked: {}", e);
+                }
+            }
+            next = accepted.recv() => {
+                // the carrier is gone once no more streams can arrive
+                let Some((stream, client_addr)) = next else {
+                    break;
+                };
+                let client_addr = client_addr.unwrap_or(carrier_addr);
+                let pool = pool.clone();
+                let config = config.clone();
+                let span = session_span(client_addr);
+                streams.spawn(
+                    async move {
+                        let _guard = ConnectionGuard::new();
+                        let res = forward_stream(stream, client_addr, &pool, &config).await;
+                        record_result(&res);
+                        if let Err(e) = res {
+                            tracing::error!("Proxy session error: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+        }
+    }
+
+    while streams.join_next().await.is_some() {}
+
+    Ok(())
+}
 
-    if haproxy {
-        let client_addr = client_stream.peer_addr()?;
-        let server_local_addr = server_stream.local_addr()?;
+// resolves once the client hung up without sending anything, stays pending otherwise
+async fn client_closed(stream: &TcpStream) {
+    // ask the socket directly first, tokio only learns about a FIN on the next poll
+    let mut probe = [std::mem::MaybeUninit::uninit(); 1];
+    match SockRef::from(stream).peek(&mut probe) {
+        Ok(0) => return,
+        Ok(_) => return std::future::pending().await,
+        Err(e) if e.kind() != io::ErrorKind::WouldBlock => return,
+        Err(_) => {}
+    }
+
+    let mut probe = [0u8; 1];
+    match stream.peek(&mut probe).await {
+        Ok(0) | Err(_) => {}
+        Ok(_) => std::future::pending().await,
+    }
+}
 
-        let signature = [
-            0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
-        ];
+async fn forward(
+    mut client_stream: TcpStream,
+    pool: &Arc<UpstreamPool>,
+    config: &Arc<ForwardConfig>,
+    mux: Option<&MuxPool>,
+) -> anyhow::Result<()> {
+    let mut client_addr = client_stream.peer_addr()?;
+    if config.accept_proxy_protocol
+        && let Some(addr) = proxy_protocol::read_header(&mut client_stream).await?
+    {
+        tracing::info!("PROXY header from {} reports client {}", client_addr, addr);
+        Span::current().record("client", tracing::field::display(addr));
+        client_addr = addr;
+    }
 
-        let mut header = Vec::with_capacity(64);
-        header.extend_from_slice(&signature);
+    // the tunnel leg: towards the client on the home side, towards the upstream on the edge
+    let mut session = None;
+    if let Some(tunnel) = &config.tunnel
+        && tunnel.role == TunnelRole::Home
+    {
+        // authenticate before an upstream connection is spent on the client
+        session = tunnel::establish(&mut client_stream, tunnel).await?;
+        if tunnel.mux {
+            return serve_mux(client_stream, session, pool.clone(), config.clone()).await;
+        }
+    }
 
-        match (client_addr, server_local_addr) {
-            (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
-                header.extend_from_slice(&[0x21, 0x11]);
-                header.extend_from_slice(&12u16.to_be_bytes());
-                header.extend_from_slice(&src.ip().octets());
-                header.extend_from_slice(&dst.ip().octets());
-                header.extend_from_slice(&src.port().to_be_bytes());
-                header.extend_from_slice(&dst.port().to_be_bytes());
+    // the client address travels in the stream's open frame instead of a PROXY header
+    if let Some(mux) = mux {
+        let stream = tokio::select! {
+            biased;
+            () = client_closed(&client_stream) => {
+                tracing::debug!("{} disconnected before a stream was opened", client_addr);
+                return Ok(());
             }
-            (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
-                header.extend_from_slice(&[0x21, 0x21]);
-                header.extend_from_slice(&36u16.to_be_bytes());
-                header.extend_from_slice(&src.ip().octets());
-                header.extend_from_slice(&dst.ip().octets());
-                header.extend_from_slice(&src.port().to_be_bytes());
-                header.extend_from_slice(&dst.port().to_be_bytes());
+            stream = mux.open(client_addr, pool, config) => stream?,
+        };
+        tracing::info!("Forwarding {} over the multiplexed tunnel", client_addr);
+        if let Err(e) = sockopt::apply_keepalive(&client_stream, &config.keepalive) {
+            tracing::warn!("Failed to configure TCP keepalive: {}", e);
+        }
+        let limit = SessionLimit::new(config, [SockRef::from(&client_stream)])?;
+        let transferred = Transferred::default();
+        limit_session(
+            limit,
+            client_addr,
+            "multiplexed tunnel",
+            &transferred,
+            mux::pump(
+                &mut client_stream,
+                stream,
+                &transferred.up,
+                &transferred.down,
+            ),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    // the handshake is consumed to pick the upstream and replayed once connected
+    let mut handshake = Vec::new();
+    let mut pool = pool.as_ref();
+    if pool.has_routes() {
+        let (hostname, read) = handshake::read_hostname(&mut client_stream).await?;
+        if let Some(hostname) = &hostname {
+            tracing::debug!("Handshake from {} asks for {}", client_addr, hostname);
+        }
+        pool = pool.route(hostname.as_deref());
+        handshake = read;
+    }
+
+    // scanners connect and drop right away, the backend should never see those
+    let (server_stream, lease) = tokio::select! {
+        biased;
+        () = client_closed(&client_stream) => {
+            tracing::debug!("{} disconnected before the upstream was connected", client_addr);
+            return Ok(());
+        }
+        res = pool.connect(config) => res?,
+    };
+    tracing::info!(
+        "Forwarding {} to upstream {}",
+        client_addr,
+        lease.upstream().name()
+    );
+
+    if let Err(e) = sockopt::apply_keepalive(&client_stream, &config.keepalive) {
+        tracing::warn!("Failed to configure TCP keepalive: {}", e);
+    }
+
+    let mut server_stream = match server_stream {
+        UpstreamStream::Tcp(stream) => stream,
+        #[cfg(unix)]
+        UpstreamStream::Unix(mut stream) => {
+            // the header describes the player's connection, the socket hop has no addresses
+            if config.haproxy_support {
+                let local_addr = client_stream.local_addr();
+                send_proxy_header(&mut stream, client_addr, local_addr, config).await?;
             }
-            _ => return Err(anyhow::anyhow!("Mismatched IP families for PROXY v2")),
+            stream.write_all(&handshake).await?;
+            let limit = SessionLimit::new(
+                config,
+                [SockRef::from(&client_stream), SockRef::from(&stream)],
+            )?;
+            let transferred = Transferred::default();
+            limit_session(
+                limit,
+                client_addr,
+                &lease.upstream().name(),
+                &transferred,
+                relay::relay_unix(
+                    &mut client_stream,
+                    &mut stream,
+                    config,
+                    session,
+                    &transferred,
+                ),
+            )
+            .await?;
+            return Ok(());
         }
+    };
 
-        server_stream.write_all(&header).await?;
+    if let Err(e) = sockopt::apply_keepalive(&server_stream, &config.keepalive) {
+        tracing::warn!("Failed to configure TCP keepalive: {}", e);
     }
 
-    copy_bidirectional(&mut client_stream, &mut server_stream).await?;
+    if config.haproxy_support {
+        let local_addr = server_stream.local_addr();
+        send_proxy_header(&mut server_stream, client_addr, local_addr, config).await?;
+    }
+    server_stream.write_all(&handshake).await?;
+
+    if let Some(tunnel) = &config.tunnel
+        && tunnel.role == TunnelRole::Edge
+    {
+        match tunnel::establish(&mut server_stream, tunnel).await {
+            Ok(established) => session = established,
+            Err(e) => {
+                let _ = server_stream.shutdown().await;
+                return Err(e);
+            }
+        }
+    }
+
+    let limit = SessionLimit::new(
+        config,
+        [SockRef::from(&client_stream), SockRef::from(&server_stream)],
+    )?;
+    let transferred = Transferred::default();
+    limit_session(
+        limit,
+        client_addr,
+        &lease.upstream().name(),
+        &transferred,
+        relay(
+            &mut client_stream,
+            &mut server_stream,
+            config,
+            session,
+            &transferred,
+        ),
+    )
+    .await?;
 
     Ok(())
 }
 
 async fn listener_handle(
     listener: TcpListener,
-    server_addr: SocketAddr,
-    haproxy: bool,
-    haproxy_version: HAProxyVersion,
+    pool: Arc<UpstreamPool>,
+    config: Arc<ForwardConfig>,
+    mux: Option<Arc<MuxPool>>,
+    geoip: Option<Arc<GeoIp>>,
     protocol: &str,
-) {
+    updates: &mut watch::Receiver<Option<Arc<ForwardConfig>>>,
+) -> JoinSet<()> {
     tracing::info!("Register {} forward worker.", protocol);
+    let mut sessions = JoinSet::new();
+    let mut accept_limit = config
+        .max_accept_rate
+        .map(|rate| TokenBucket::new(rate, rate));
+    let per_ip_limit = config.max_active_per_ip.map(PerIpLimit::new);
+    let mut rejected = 0u64;
+    let mut last_reject_log = Instant::now();
+    let mut refused = 0u64;
+    let mut last_refuse_log = Instant::now();
+    let mut filtered = 0u64;
+    let mut last_filter_log = Instant::now();
+    let mut fd_backoff = FD_BACKOFF_MIN;
+    let (players_tx, mut players) = mpsc::channel(PLAYER_QUEUE);
     loop {
-        match listener.accept().await {
+        let (accepted, classified) = tokio::select! {
+            Some(res) = sessions.join_next() => {
+                if let Err(e) = res
+                    && e.is_panic()
+                {
+                    tracing::error!("Proxy session panicked: {}", e);
+                }
+                continue;
+            }
+            // the listener is dropped on return, the caller drains what is still running
+            Ok(()) = updates.changed() => return sessions,
+            // players whose first bytes were checked for the heartbeat magic
+            Some(player) = players.recv() => (Ok(player), true),
+            accepted = listener.accept() => (accepted, false),
+        };
+
+        match accepted {
             Ok((client_stream, addr)) => {
-                // heartbeat server
-                let wan_host = WAN_ADDR.get().unwrap().read().await.ip();
-                if addr.ip().to_canonical() == wan_host {
-                    let mut buf = [0u8; 4];
-                    match client_stream.peek(&mut buf).await {
-                        Ok(n) if n >= 4 && &buf == b"hbpk" => {
-                            tokio::spawn(heartbeat_server(client_stream));
-                            continue;
-                        }
-                        _ => {
-                            tracing::info!(
-                                "Internal redirection: Loopback connection from player at {}",
+                fd_backoff = FD_BACKOFF_MIN;
+                if !classified {
+                    if let Some(bucket) = &mut accept_limit
+                        && !bucket.try_take()
+                    {
+                        // dropping the stream closes it before any task is spawned
+                        drop(client_stream);
+                        METRICS.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                        rejected += 1;
+                        if last_reject_log.elapsed() >= REJECT_LOG_INTERVAL {
+                            tracing::warn!(
+                                "Accept rate limit exceeded, dropped {} connections (latest from {})",
+                                rejected,
                                 addr
                             );
+                            rejected = 0;
+                            last_reject_log = Instant::now();
                         }
+                        continue;
+                    }
+
+                    // heartbeat server
+                    let wan_host = WAN_ADDR.get().unwrap().read().await.ip();
+                    let hairpin = addr.ip().to_canonical() == wan_host;
+                    let checker = config
+                        .heartbeat_sources
+                        .iter()
+                        .any(|source| source.contains(addr.ip()));
+                    if hairpin || checker || heartbeat::probe_from(addr.ip()) {
+                        // a client that never speaks must not stall the accept loop, so the magic
+                        // is awaited in a task of its own and players come back through the queue
+                        let players = players_tx.clone();
+                        sessions.spawn(async move {
+                            if let Some(client_stream) =
+                                classify(client_stream, addr, hairpin).await
+                            {
+                                let _ = players.send((client_stream, addr)).await;
+                            }
+                        });
+                        continue;
                     }
                 }
 
-                tracing::info!("New connection from: {}", addr);
-                tokio::spawn(async move {
-                    if let Err(e) = match haproxy_version {
-                        HAProxyVersion::V1 => forward(client_stream, server_addr, haproxy).await,
-                        HAProxyVersion::V2 => forward_v2(client_stream, server_addr, haproxy).await,
-                    } {
-                        tracing::error!("Proxy session error: {}", e);
+                let location = geoip
+                    .as_ref()
+                    .map(|geoip| (geoip, geoip.locate(addr.ip().to_canonical())));
+                if let Some((geoip, location)) = &location
+                    && !geoip.allows(location)
+                {
+                    drop(client_stream);
+                    METRICS.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                    filtered += 1;
+                    if last_filter_log.elapsed() >= REJECT_LOG_INTERVAL {
+                        tracing::warn!(
+                            "Country filter dropped {} connections (latest from {} in {})",
+                            filtered,
+                            addr,
+                            location
+                        );
+                        filtered = 0;
+                        last_filter_log = Instant::now();
                     }
-                });
+                    continue;
+                }
+
+                let slot = match &per_ip_limit {
+                    Some(limit) => match limit.try_acquire(addr.ip().to_canonical()) {
+                        Some(slot) => Some(slot),
+                        None => {
+                            drop(client_stream);
+                            METRICS.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                            refused += 1;
+                            if last_refuse_log.elapsed() >= REJECT_LOG_INTERVAL {
+                                tracing::warn!(
+                                    "Per-IP session limit reached, refused {} connections (latest from {})",
+                                    refused,
+                                    addr
+                                );
+                                refused = 0;
+                                last_refuse_log = Instant::now();
+                            }
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+
+                match &location {
+                    Some((_, location)) => {
+                        metrics::count_country(location.country.as_deref());
+                        tracing::debug!("New connection from: {} ({})", addr, location);
+                    }
+                    None => tracing::debug!("New connection from: {}", addr),
+                }
+                let config = config.clone();
+                let pool = pool.clone();
+                let mux = mux.clone();
+                let span = session_span(addr);
+                sessions.spawn(
+                    async move {
+                        // held for the whole session, released however forward ends
+                        let _slot = slot;
+                        let _guard = ConnectionGuard::new();
+                        let res = forward(client_stream, &pool, &config, mux.as_deref()).await;
+                        record_result(&res);
+                        if let Err(e) = res {
+                            tracing::error!("Proxy session error: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+            // the pending connection stays queued, so retrying right away would only spin
+            // until sessions close and give descriptors back
+            Err(e) if out_of_descriptors(&e) => {
+                tracing::warn!(
+                    "Out of file descriptors with {} sessions open ({}), pausing accept for {}s. \
+                     Raise the open file limit or cap sessions with forward.max_active_per_ip.",
+                    sessions.len(),
+                    e,
+                    fd_backoff.as_secs()
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(fd_backoff) => {}
+                    Ok(()) = updates.changed() => return sessions,
+                }
+                fd_backoff = (fd_backoff * 2).min(FD_BACKOFF_MAX);
             }
             Err(e) => {
                 tracing::error!("Accept failed: {}", e);
 
-                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                tokio::time::sleep(ACCEPT_RETRY).await;
             }
         }
     }
 }
 
-async fn heartbeat_server(mut stream: TcpStream) {
-    let mut buf = [0u8; 64];
-    loop {
-        match stream.read(&mut buf).await {
-            Ok(0) => {
-                tracing::info!("Heartbeat client disconnected.");
-                break;
-            }
-            Ok(n) => {
-                if &buf[..n] == b"hbpk" {
-                    if let Err(e) = stream.write_all(b"hbre").await {
-                        tracing::error!("Failed to send response to heartbeat client: {}", e);
-                        break;
-                    }
-                } else {
-                    tracing::warn!(
-                        "Received unknown data from heartbeat client: {:?}",
-                        &buf[..n]
-                    );
-                }
+// accept fails with these once the process or the whole system is out of descriptors
+#[cfg(unix)]
+const DESCRIPTOR_ERRORS: &[i32] = &[libc::EMFILE, libc::ENFILE];
+// WSAEMFILE
+#[cfg(windows)]
+const DESCRIPTOR_ERRORS: &[i32] = &[10024];
+#[cfg(not(any(unix, windows)))]
+const DESCRIPTOR_ERRORS: &[i32] = &[];
+
+fn out_of_descriptors(e: &io::Error) -> bool {
+    e.raw_os_error()
+        .is_some_and(|code| DESCRIPTOR_ERRORS.contains(&code))
+}
+
+fn listen_addr(config: &ForwardConfig, unspecified: IpAddr) -> anyhow::Result<SocketAddr> {
+    match config.bind_addr {
+        None => Ok(SocketAddr::new(unspecified, config.local_port)),
+        Some(ip) if ip.is_ipv4() == unspecified.is_ipv4() => {
+            Ok(SocketAddr::new(ip, config.local_port))
+        }
+        Some(ip) => Err(anyhow!(
+            "bind_addr {} does not match the {} listener",
+            ip,
+            if unspecified.is_ipv4() {
+                "IPv4"
+            } else {
+                "IPv6"
             }
-            Err(e) => {
-                tracing::error!("Read error from heartbeat client: {}", e);
-                break;
+        )),
+    }
+}
+
+// serves the connection if it opens with the heartbeat magic, hands a player back otherwise
+async fn classify(client_stream: TcpStream, addr: SocketAddr, hairpin: bool) -> Option<TcpStream> {
+    // room for the stats flag in front of the magic
+    let mut buf = vec![0u8; heartbeat::magic().len() + 1];
+    match tokio::time::timeout(PEEK_TIMEOUT, client_stream.peek(&mut buf)).await {
+        Ok(Ok(n)) if heartbeat::is_request(&buf[..n]) => {
+            heartbeat::serve(client_stream).await;
+            None
+        }
+        _ => {
+            if hairpin {
+                tracing::info!(
+                    "Internal redirection: Loopback connection from player at {}",
+                    addr
+                );
             }
+            Some(client_stream)
         }
     }
 }
 
+fn mark_ready() {
+    LISTENER_READY.store(true, Ordering::Relaxed);
+    #[cfg(unix)]
+    crate::systemd::notify_ready();
+}
+
 pub async fn run(config: ForwardConfig) -> anyhow::Result<()> {
-    let host_with_port = format!("{}:{}", config.server_host, config.server_port);
-
-    let ipv6_res = async {
-        let mut server_addr = lookup_host(&host_with_port)
-            .await?
-            .find(|addr| addr.is_ipv6())
-            .ok_or_else(|| anyhow!("No IPv6 found"))?;
-        server_addr.set_port(config.server_port);
-
-        let socket = TcpSocket::new_v6()?;
-        let local_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), config.local_port);
-        socket.set_reuseaddr(true)?;
-        #[cfg(unix)]
-        socket.set_reuseport(true)?;
-        socket.set_nodelay(true)?;
-        socket.bind(local_addr)?;
-        let listener = socket.listen(1024)?;
+    LISTENER_READY.store(false, Ordering::Relaxed);
+    CONFIG.send_if_modified(|current| {
+        current.get_or_insert_with(|| Arc::new(config));
+        false
+    });
+    let mut updates = CONFIG.subscribe();
+
+    loop {
+        let config = updates
+            .borrow_and_update()
+            .clone()
+            .expect("the forward configuration is set before subscribing");
+        let sessions = serve(config.clone(), &mut updates).await?;
+        tracing::info!("Forward configuration reloaded, rebinding the listener");
+        tokio::spawn(drain(
+            sessions,
+            std::time::Duration::from_secs(config.drain_timeout),
+        ));
+    }
+}
 
+// runs one listener until the configuration changes and returns its live sessions
+async fn serve(
+    config: Arc<ForwardConfig>,
+    updates: &mut watch::Receiver<Option<Arc<ForwardConfig>>>,
+) -> anyhow::Result<JoinSet<()>> {
+    let pool = Arc::new(UpstreamPool::new(&config).await?);
+    let mux = config
+        .tunnel
+        .as_ref()
+        .filter(|tunnel| tunnel.mux && tunnel.role == TunnelRole::Edge)
+        .map(|tunnel| Arc::new(MuxPool::new(tunnel.mux_connections)));
+    let geoip = config
+        .geoip_database
+        .as_ref()
+        .map(|path| Arc::new(GeoIp::open(path, config.allowed_countries.clone())));
+    let targets = pool.names().join(", ");
+    for (hostname, target) in &config.routes {
         tracing::info!(
-            "Listening on [::]:{} (IPv6) -> Target: {}",
-            config.local_port,
-            server_addr
+            "Routing hostname {} to {}:{}",
+            hostname,
+            target.host,
+            target.port
         );
-        listener_handle(
-            listener,
-            server_addr,
-            config.haproxy_support,
-            config.haproxy_version,
-            "IPv6",
-        )
-        .await;
-        Ok::<(), anyhow::Error>(())
     }
+
+    let v6 = match config.bind_addr {
+        // an IPv4 bind_addr leaves no IPv6 listener to try
+        Some(IpAddr::V4(_)) => None,
+        _ => Some(bind(&config, IpAddr::V6(Ipv6Addr::UNSPECIFIED))),
+    };
+    let (listener, local_addr, protocol) = match v6 {
+        Some(Ok((listener, local_addr))) => (listener, local_addr, "IPv6"),
+        v6 => {
+            if let Some(Err(e)) = v6 {
+                tracing::warn!("IPv6 setup failed: {}. Falling back to IPv4...", e);
+            }
+            let (listener, local_addr) = bind(&config, IpAddr::V4(Ipv4Addr::UNSPECIFIED))?;
+            (listener, local_addr, "IPv4")
+        }
+    };
+    mark_ready();
+
+    tracing::info!(
+        "Listening on {} ({}) -> Target: {}",
+        local_addr,
+        protocol,
+        targets
+    );
+    Ok(listener_handle(listener, pool, config, mux, geoip, protocol, updates).await)
+}
+
+fn bind(config: &ForwardConfig, unspecified: IpAddr) -> anyhow::Result<(TcpListener, SocketAddr)> {
+    let socket = match unspecified {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    let local_addr = listen_addr(config, unspecified)?;
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.set_nodelay(true)?;
+    if unspecified.is_ipv6() && config.bind_addr.is_none() {
+        SockRef::from(&socket).set_only_v6(false)?;
+    }
+    socket.bind(local_addr)?;
+    Ok((socket.listen(config.listen_backlog)?, local_addr))
+}
+
+// lets the sessions of a replaced listener finish, closing whatever outlasts the timeout
+async fn drain(mut sessions: JoinSet<()>, timeout: std::time::Duration) {
+    if sessions.is_empty() {
+        return;
+    }
+    tracing::info!(
+        "Draining {} sessions of the previous listener for up to {}s",
+        sessions.len(),
+        timeout.as_secs()
+    );
+    let finished = tokio::time::timeout(timeout, async {
+        while let Some(res) = sessions.join_next().await {
+            if let Err(e) = res
+                && e.is_panic()
+            {
+                tracing::error!("Proxy session panicked: {}", e);
+            }
+        }
+    })
     .await;
+    match finished {
+        Ok(()) => tracing::info!("Previous listener drained"),
+        Err(_) => {
+            tracing::warn!(
+                "Drain timeout elapsed, closing {} remaining sessions",
+                sessions.len()
+            );
+            sessions.shutdown().await;
+        }
+    }
+}
 
-    if let Err(e) = ipv6_res {
-        tracing::warn!("IPv6 setup failed: {}. Falling back to IPv4...", e);
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncReadExt, sync::RwLock};
 
-        let mut server_addr = lookup_host(&host_with_port)
-            .await?
-            .find(|addr| addr.is_ipv4())
-            .ok_or_else(|| anyhow!("No IPv4 found"))?;
-        server_addr.set_port(config.server_port);
+    use super::*;
+    use crate::cidr::Cidr;
 
-        let socket = TcpSocket::new_v4()?;
-        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), config.local_port);
-        socket.set_reuseaddr(true)?;
-        #[cfg(unix)]
-        socket.set_reuseport(true)?;
-        socket.set_nodelay(true)?;
-        socket.bind(local_addr)?;
-        let listener = socket.listen(1024)?;
+    fn config_for(upstream: SocketAddr) -> Arc<ForwardConfig> {
+        Arc::new(ForwardConfig {
+            server_host: upstream.ip().to_string(),
+            server_port: upstream.port(),
+            ..ForwardConfig::default()
+        })
+    }
 
-        tracing::info!(
-            "Listening on 0.0.0.0:{} (IPv4) -> Target: {}",
-            config.local_port,
-            server_addr
+    #[tokio::test]
+    async fn client_closing_early_never_reaches_the_upstream() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = config_for(upstream.local_addr().unwrap());
+        let pool = Arc::new(UpstreamPool::new(&config).await.unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        drop(client);
+        // wait for the FIN, so the session sees a client that is already gone
+        accepted.readable().await.unwrap();
+
+        let session = forward(accepted, &pool, &config, None);
+        tokio::time::timeout(std::time::Duration::from_secs(1), session)
+            .await
+            .expect("the session outlived its client")
+            .unwrap();
+
+        let dialled =
+            tokio::time::timeout(std::time::Duration::from_millis(200), upstream.accept()).await;
+        assert!(dialled.is_err(), "the upstream saw a connection");
+    }
+
+    // collects what the session logs so a test can read it back
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn session_cut_off_after_the_grace_still_records_its_bytes() {
+        let limit = SessionLimit {
+            after: std::time::Duration::ZERO,
+            grace: std::time::Duration::from_millis(10),
+            sockets: Vec::new(),
+        };
+        let transferred = Transferred::default();
+        let before = (
+            METRICS.bytes_up.load(Ordering::Relaxed),
+            METRICS.bytes_down.load(Ordering::Relaxed),
+        );
+        // moves some bytes, then ignores the shutdown and never finishes
+        let relay = async {
+            transferred.up.fetch_add(1234, Ordering::Relaxed);
+            transferred.down.fetch_add(56, Ordering::Relaxed);
+            std::future::pending::<io::Result<()>>().await
+        };
+
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let res = {
+            // the test runtime polls on this thread, so the default covers the whole session
+            let _guard = tracing::subscriber::set_default(subscriber);
+            limit_session(
+                Some(limit),
+                "127.0.0.1:1".parse().unwrap(),
+                "test",
+                &transferred,
+                relay,
+            )
+            .await
+        };
+
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(METRICS.bytes_up.load(Ordering::Relaxed) - before.0 >= 1234);
+        assert!(METRICS.bytes_down.load(Ordering::Relaxed) - before.1 >= 56);
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.contains("cut off after 1234 bytes up, 56 bytes down"),
+            "{logs}"
         );
-        listener_handle(
-            listener,
-            server_addr,
-            config.haproxy_support,
-            config.haproxy_version,
-            "IPv4",
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn descriptor_exhaustion_is_told_apart_from_other_accept_errors() {
+        let os_error = io::Error::from_raw_os_error;
+        assert!(out_of_descriptors(&os_error(libc::EMFILE)));
+        assert!(out_of_descriptors(&os_error(libc::ENFILE)));
+        assert!(!out_of_descriptors(&os_error(libc::ECONNABORTED)));
+        assert!(!out_of_descriptors(&io::Error::other("not an OS error")));
+    }
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        addr
+    }
+
+    // run keeps its configuration in a global, so a single instance on a runtime of its own
+    // serves every test going through the listener; loopback counts as a heartbeat source
+    static LISTENER: LazyLock<SocketAddr> = LazyLock::new(|| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let _ = WAN_ADDR.set(RwLock::new(SocketAddr::from(([0, 0, 0, 0], 0))));
+                let upstream = echo_server().await;
+                // run binds the port again with SO_REUSEADDR
+                let port = std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port();
+                let config = ForwardConfig {
+                    local_port: port,
+                    heartbeat_sources: vec![Cidr::try_from("127.0.0.1/32".to_string()).unwrap()],
+                    ..(*config_for(upstream)).clone()
+                };
+                tx.send(SocketAddr::from(([127, 0, 0, 1], port))).unwrap();
+                run(config).await.unwrap();
+            });
+        });
+        rx.recv().unwrap()
+    });
+
+    async fn connect() -> TcpStream {
+        let addr = *LISTENER;
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                if LISTENER_READY.load(Ordering::Relaxed)
+                    && let Ok(stream) = TcpStream::connect(addr).await
+                {
+                    return stream;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the listener never came up")
+    }
+
+    #[tokio::test]
+    async fn listener_relays_bytes_to_the_upstream_and_back() {
+        let mut client = connect().await;
+        let payload: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        client.write_all(&payload).await.unwrap();
+
+        let mut echoed = vec![0u8; payload.len()];
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            client.read_exact(&mut echoed),
         )
-        .await;
+        .await
+        .expect("the echo never came back")
+        .unwrap();
+        assert_eq!(echoed, payload);
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn silent_checker_connection_does_not_hold_up_accepts() {
+        // loopback is a heartbeat source, so this one is waited on for the magic
+        let _silent = connect().await;
+
+        let mut client = connect().await;
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        tokio::time::timeout(PEEK_TIMEOUT / 2, client.read_exact(&mut echoed))
+            .await
+            .expect("the accept loop waited on the silent client")
+            .unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn listener_answers_heartbeats_from_a_configured_source() {
+        let mut client = connect().await;
+        let response = heartbeat::exchange(&mut client).await.unwrap();
+        assert!(response.starts_with(heartbeat::RESPONSE));
+    }
 }
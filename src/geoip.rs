@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use maxminddb::{Reader, geoip2};
+
+// players reconnect from the same few addresses, a small cache absorbs nearly every lookup
+const CACHE_SIZE: usize = 4096;
+
+// both parts are None when the database cannot place the address (LAN, errors)
+#[derive(Clone, Default)]
+pub struct Location {
+    pub country: Option<String>,
+    // only City databases carry it
+    pub city: Option<String>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.country, &self.city) {
+            (Some(country), Some(city)) => write!(f, "{}, {}", city, country),
+            (Some(country), None) => write!(f, "{}", country),
+            _ => write!(f, "unknown location"),
+        }
+    }
+}
+
+pub struct GeoIp {
+    reader: Option<Reader<Vec<u8>>>,
+    allowed_countries: Option<Vec<String>>,
+    cache: Mutex<HashMap<IpAddr, Location>>,
+    lookup_failed: AtomicBool,
+}
+
+impl GeoIp {
+    // a missing or corrupt database disables the lookups, never the listener
+    pub fn open(path: &Path, allowed_countries: Option<Vec<String>>) -> Self {
+        let reader = match Reader::open_readfile(path) {
+            Ok(reader) => {
+                tracing::info!(
+                    "Loaded GeoIP database {} ({})",
+                    path.display(),
+                    reader.metadata.database_type
+                );
+                Some(reader)
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to open GeoIP database {}: {}, connections will not be filtered by country",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        };
+
+        Self {
+            reader,
+            allowed_countries: allowed_countries
+                .map(|codes| codes.iter().map(|c| c.to_ascii_uppercase()).collect()),
+            cache: Mutex::new(HashMap::new()),
+            lookup_failed: AtomicBool::new(false),
+        }
+    }
+
+    // country as an ISO 3166 code, city by its English name
+    pub fn locate(&self, ip: IpAddr) -> Location {
+        let Some(reader) = &self.reader else {
+            return Location::default();
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(hit) = cache.get(&ip) {
+            return hit.clone();
+        }
+
+        // a Country database decodes into the same record, just without the city
+        let location = match reader.lookup::<geoip2::City>(ip) {
+            Ok(Some(record)) => Location {
+                country: record
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .map(str::to_owned),
+                city: record
+                    .city
+                    .and_then(|city| city.names)
+                    .and_then(|names| names.get("en").map(|name| name.to_string())),
+            },
+            Ok(None) => Location::default(),
+            Err(e) => {
+                if !self.lookup_failed.swap(true, Ordering::Relaxed) {
+                    tracing::warn!(
+                        "GeoIP lookup for {} failed: {}, such connections are let through",
+                        ip,
+                        e
+                    );
+                }
+                Location::default()
+            }
+        };
+
+        if cache.len() >= CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(ip, location.clone());
+        location
+    }
+
+    // fails open: addresses without a known country are always allowed
+    pub fn allows(&self, location: &Location) -> bool {
+        match (&self.allowed_countries, &location.country) {
+            (Some(allowed), Some(country)) => allowed.iter().any(|c| c == country),
+            _ => true,
+        }
+    }
+}
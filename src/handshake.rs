@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// an address is at most 255 characters, the whole packet stays far below this
+const MAX_PACKET: usize = 1024;
+
+enum Parse {
+    Incomplete,
+    Host(String),
+    NotHandshake,
+}
+
+// reads the Java edition handshake and returns the requested hostname together with
+// every byte consumed, which the caller has to replay to the upstream
+pub async fn read_hostname(stream: &mut TcpStream) -> anyhow::Result<(Option<String>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(256);
+    let deadline = tokio::time::Instant::now() + READ_TIMEOUT;
+    loop {
+        match parse(&buf) {
+            Parse::Host(host) => return Ok((Some(host), buf)),
+            Parse::NotHandshake => return Ok((None, buf)),
+            Parse::Incomplete => {}
+        }
+
+        let mut chunk = [0u8; 512];
+        // a silent client is not ours to judge, the default upstream decides what to do
+        let Ok(n) = tokio::time::timeout_at(deadline, stream.read(&mut chunk)).await else {
+            return Ok((None, buf));
+        };
+        let n = n?;
+        if n == 0 {
+            return Ok((None, buf));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// length, packet id 0x00, protocol version, server address, port, next state
+fn parse(buf: &[u8]) -> Parse {
+    let (len, offset) = match varint(buf) {
+        Some(Ok(v)) => v,
+        Some(Err(())) => return Parse::NotHandshake,
+        None => return Parse::Incomplete,
+    };
+    // legacy pings start with 0xFE and decode to a huge length
+    if len == 0 || len as usize > MAX_PACKET {
+        return Parse::NotHandshake;
+    }
+    let Some(packet) = buf[offset..].get(..len as usize) else {
+        return Parse::Incomplete;
+    };
+
+    // the packet is complete, so anything missing from here on means it is malformed
+    address(packet).map_or(Parse::NotHandshake, Parse::Host)
+}
+
+fn address(packet: &[u8]) -> Option<String> {
+    let (id, mut pos) = varint(packet)?.ok()?;
+    if id != 0 {
+        return None;
+    }
+    let (_protocol, n) = varint(&packet[pos..])?.ok()?;
+    pos += n;
+    let (len, n) = varint(&packet[pos..])?.ok()?;
+    pos += n;
+    let raw = packet.get(pos..pos + len as usize)?;
+    let raw = std::str::from_utf8(raw).ok()?;
+
+    // Forge appends "\0FML\0" and clients resolving SRV records keep the trailing dot
+    let host = raw.split('\0').next()?.trim_end_matches('.');
+    Some(host.to_ascii_lowercase())
+}
+
+// None while more bytes are needed, Err once the VarInt runs past five bytes
+fn varint(buf: &[u8]) -> Option<Result<(u32, usize), ()>> {
+    let mut value = 0u32;
+    for (i, &b) in buf.iter().enumerate().take(5) {
+        value |= u32::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some(Ok((value, i + 1)));
+        }
+    }
+    if buf.len() >= 5 { Some(Err(())) } else { None }
+}
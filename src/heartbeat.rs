@@ -0,0 +1,335 @@
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::{Mutex, OnceLock, atomic::Ordering},
+    time::Duration,
+};
+
+use anyhow::bail;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    metrics::{self, METRICS},
+    random,
+};
+
+pub const RESPONSE: &[u8] = b"hbre";
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+const STATS_VERSION: u8 = 1;
+const STATS_LEN: usize = 12;
+// sent before the magic by clients that want the stats frame; a configured magic is text,
+// so it never starts with this byte, and the random one is chosen not to
+const STATS_FLAG: u8 = 0xFF;
+
+pub struct ServerStats {
+    pub active_connections: u32,
+    pub uptime: Duration,
+}
+
+// clients that did not ask for stats get the bare ack they expect
+fn encode_response(stats: bool) -> Vec<u8> {
+    if !stats {
+        return RESPONSE.to_vec();
+    }
+    let active = METRICS.active_connections.load(Ordering::Relaxed) as u32;
+
+    let mut resp = Vec::with_capacity(RESPONSE.len() + 2 + STATS_LEN);
+    resp.extend_from_slice(RESPONSE);
+    resp.extend_from_slice(&[STATS_VERSION, STATS_LEN as u8]);
+    resp.extend_from_slice(&active.to_be_bytes());
+    resp.extend_from_slice(&metrics::uptime().as_secs().to_be_bytes());
+    resp
+}
+
+pub fn decode_stats(resp: &[u8]) -> Option<ServerStats> {
+    let frame = resp.strip_prefix(RESPONSE)?;
+    let [STATS_VERSION, len, body @ ..] = frame else {
+        return None;
+    };
+    let body = body.get(..*len as usize).filter(|b| b.len() >= STATS_LEN)?;
+
+    Some(ServerStats {
+        active_connections: u32::from_be_bytes(body[0..4].try_into().ok()?),
+        uptime: Duration::from_secs(u64::from_be_bytes(body[4..12].try_into().ok()?)),
+    })
+}
+
+static MAGIC: OnceLock<Vec<u8>> = OnceLock::new();
+static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+// timestamp (8) + nonce (8) + HMAC-SHA256 (32)
+const AUTH_LEN: usize = 48;
+const MAX_CLOCK_SKEW: u64 = 60;
+// challenges accepted within the skew window, so a captured probe cannot be replayed
+// while its timestamp is still fresh
+const MAX_SEEN: usize = 4096;
+static SEEN: Mutex<VecDeque<[u8; 16]>> = Mutex::new(VecDeque::new());
+
+pub fn init(configured: Option<&str>, secret: Option<&str>) {
+    if let Some(magic) = configured {
+        let _ = MAGIC.set(magic.as_bytes().to_vec());
+    }
+    if let Some(secret) = secret {
+        let _ = SECRET.set(secret.as_bytes().to_vec());
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn mac(secret: &[u8], challenge: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key size");
+    mac.update(challenge);
+    mac
+}
+
+fn sign(secret: &[u8]) -> Vec<u8> {
+    let challenge = [unix_time().to_be_bytes(), random::bytes()].concat();
+    let tag = mac(secret, &challenge).finalize().into_bytes();
+    [challenge, tag.to_vec()].concat()
+}
+
+pub fn request() -> Vec<u8> {
+    let mut req = vec![STATS_FLAG];
+    req.extend_from_slice(magic());
+    if let Some(secret) = SECRET.get() {
+        req.extend_from_slice(&sign(secret));
+    }
+    req
+}
+
+fn authenticate(auth: &[u8]) -> bool {
+    match SECRET.get() {
+        Some(secret) => verify(secret, auth, &mut SEEN.lock().unwrap()),
+        None => true,
+    }
+}
+
+fn verify(secret: &[u8], auth: &[u8], seen: &mut VecDeque<[u8; 16]>) -> bool {
+    let (challenge, tag) = auth.split_at(16);
+    let sent_at = u64::from_be_bytes(challenge[..8].try_into().unwrap());
+    let now = unix_time();
+    // bounding the timestamp keeps a captured heartbeat from being replayed later on
+    if now.abs_diff(sent_at) > MAX_CLOCK_SKEW || mac(secret, challenge).verify_slice(tag).is_err() {
+        return false;
+    }
+
+    // and remembering the fresh ones keeps it from being replayed right away
+    seen.retain(|c| {
+        let t = u64::from_be_bytes(c[..8].try_into().unwrap());
+        now.abs_diff(t) <= MAX_CLOCK_SKEW
+    });
+    let challenge: [u8; 16] = challenge.try_into().unwrap();
+    if seen.contains(&challenge) {
+        return false;
+    }
+    if seen.len() == MAX_SEEN {
+        seen.pop_front();
+    }
+    seen.push_back(challenge);
+    true
+}
+
+// probes through an outside relay arrive from the relay's address rather than the WAN one,
+// so the listener also checks connections from there for the magic while one is in flight
+static PROBE_SOURCES: Mutex<Vec<IpAddr>> = Mutex::new(Vec::new());
+
+pub fn probe_from(ip: IpAddr) -> bool {
+    PROBE_SOURCES.lock().unwrap().contains(&ip.to_canonical())
+}
+
+pub struct ProbeWindow {
+    sources: Vec<IpAddr>,
+}
+
+impl ProbeWindow {
+    pub fn open(sources: impl IntoIterator<Item = IpAddr>) -> Self {
+        let sources: Vec<IpAddr> = sources.into_iter().map(|ip| ip.to_canonical()).collect();
+        PROBE_SOURCES.lock().unwrap().extend(&sources);
+        Self { sources }
+    }
+}
+
+impl Drop for ProbeWindow {
+    fn drop(&mut self) {
+        // other checks may still wait on the same relay
+        let mut open = PROBE_SOURCES.lock().unwrap();
+        for source in &self.sources {
+            if let Some(i) = open.iter().position(|ip| ip == source) {
+                open.swap_remove(i);
+            }
+        }
+    }
+}
+
+// one request/acknowledgement round, returns the raw acknowledgement
+pub async fn exchange(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    tokio::time::timeout(IO_TIMEOUT, stream.write_all(&request())).await??;
+
+    let mut buf = [0u8; 64];
+    let n = tokio::time::timeout(IO_TIMEOUT, stream.read(&mut buf)).await??;
+    if n == 0 {
+        bail!("Connection closed by remote peer");
+    }
+    if n < RESPONSE.len() || &buf[..RESPONSE.len()] != RESPONSE {
+        bail!("Invalid heartbeat response: {:?}", &buf[..n]);
+    }
+
+    Ok(buf[..n].to_vec())
+}
+
+// detector and client live in the same process, so a fresh random value needs no coordination
+pub fn magic() -> &'static [u8] {
+    MAGIC.get_or_init(|| {
+        let mut magic = random::bytes::<16>();
+        if magic[0] == STATS_FLAG {
+            magic[0] = 0;
+        }
+        magic.to_vec()
+    })
+}
+
+// whether a connection opens with a heartbeat request, with or without the stats flag
+pub fn is_request(head: &[u8]) -> bool {
+    head.strip_prefix(&[STATS_FLAG])
+        .unwrap_or(head)
+        .starts_with(magic())
+}
+
+// fills buf with the magic and auth, returning whether the stats flag came before them
+async fn read_request(stream: &mut TcpStream, buf: &mut [u8]) -> std::io::Result<bool> {
+    stream.read_exact(&mut buf[..1]).await?;
+    let stats = buf[0] == STATS_FLAG;
+    let start = if stats { 0 } else { 1 };
+    stream.read_exact(&mut buf[start..]).await?;
+    Ok(stats)
+}
+
+pub async fn serve(mut stream: TcpStream) {
+    let auth_len = if SECRET.get().is_some() { AUTH_LEN } else { 0 };
+    let mut buf = vec![0u8; magic().len() + auth_len];
+    loop {
+        match read_request(&mut stream, &mut buf).await {
+            Ok(stats) => {
+                let (received, auth) = buf.split_at(magic().len());
+                if received != magic() {
+                    tracing::warn!("Received unknown data from heartbeat client: {:?}", buf);
+                    continue;
+                }
+                if !authenticate(auth) {
+                    tracing::warn!("Rejected unauthenticated heartbeat client.");
+                    break;
+                }
+                if let Err(e) = stream.write_all(&encode_response(stats)).await {
+                    tracing::error!("Failed to send response to heartbeat client: {}", e);
+                    break;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                tracing::info!("Heartbeat client disconnected.");
+                break;
+            }
+            Err(e) => {
+                tracing::error!("Read error from heartbeat client: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn served() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        tokio::spawn(serve(accepted));
+        client
+    }
+
+    #[tokio::test]
+    async fn legacy_client_gets_only_the_bare_ack() {
+        let mut client = served().await;
+        for _ in 0..2 {
+            client.write_all(magic()).await.unwrap();
+            let mut ack = [0u8; 4];
+            client.read_exact(&mut ack).await.unwrap();
+            assert_eq!(&ack, RESPONSE);
+        }
+        // nothing is left over to throw the next round out of sync
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut rest = [0u8; 1];
+        assert!(
+            matches!(client.try_read(&mut rest), Err(e) if e.kind() == std::io::ErrorKind::WouldBlock)
+        );
+    }
+
+    #[tokio::test]
+    async fn flagged_client_gets_the_stats_frame() {
+        let mut client = served().await;
+        let resp = exchange(&mut client).await.unwrap();
+        assert!(decode_stats(&resp).is_some());
+    }
+
+    #[test]
+    fn replayed_probe_is_rejected() {
+        let secret = b"shared secret";
+        let mut seen = VecDeque::new();
+        let auth = sign(secret);
+        assert!(verify(secret, &auth, &mut seen));
+        assert!(!verify(secret, &auth, &mut seen), "the replay was accepted");
+
+        // a fresh probe from the same client still gets through
+        assert!(verify(secret, &sign(secret), &mut seen));
+        assert!(!verify(b"other secret", &sign(secret), &mut seen));
+    }
+
+    #[test]
+    fn stale_probe_is_rejected() {
+        let secret = b"shared secret";
+        let challenge = [(unix_time() - 2 * MAX_CLOCK_SKEW).to_be_bytes(), [7; 8]].concat();
+        let tag = mac(secret, &challenge).finalize().into_bytes();
+        let auth = [challenge, tag.to_vec()].concat();
+        assert!(!verify(secret, &auth, &mut VecDeque::new()));
+    }
+
+    #[test]
+    fn requests_are_recognised_with_and_without_the_flag() {
+        assert!(is_request(magic()));
+        assert!(is_request(&request()));
+        assert!(!is_request(&magic()[1..]));
+        assert!(!is_request(b"\x01\x00\x00\x00"));
+    }
+
+    #[test]
+    fn probe_window_covers_only_the_relay_while_open() {
+        let relay: IpAddr = "198.51.100.9".parse().unwrap();
+        let player: IpAddr = "203.0.113.50".parse().unwrap();
+        assert!(!probe_from(relay));
+
+        let window = ProbeWindow::open([relay]);
+        let second = ProbeWindow::open([relay]);
+        // dual-stack listeners report the relay as a mapped address
+        assert!(probe_from("::ffff:198.51.100.9".parse().unwrap()));
+        assert!(!probe_from(player));
+
+        drop(window);
+        assert!(probe_from(relay), "closing one check ended the other");
+        drop(second);
+        assert!(!probe_from(relay));
+    }
+}
@@ -1,13 +1,56 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::atomic::AtomicBool};
 
-use chrono::Local;
+use chrono::{DateTime, Local};
+use config::Traversal;
 use tokio::sync::{OnceCell, RwLock};
-use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
+use tracing_subscriber::{
+    EnvFilter, Layer,
+    filter::{FilterExt, filter_fn},
+    fmt::{format::Writer, time::FormatTime},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
 
+mod build_info;
+mod cidr;
+mod circuit;
+mod cli;
 mod config;
+mod copy;
 mod ddns;
 mod forward;
+mod geoip;
+mod handshake;
+mod heartbeat;
+mod metrics;
+mod mux;
+mod natpmp;
+mod obfuscate;
+mod pidfile;
+mod proxy_protocol;
+mod random;
+mod ratelimit;
+mod reachability;
+mod relay;
+#[cfg(unix)]
+mod reload;
+mod sockopt;
+mod socks5;
+#[cfg(target_os = "linux")]
+mod splice;
+mod state;
+mod stats;
+mod statsd;
+mod status;
 mod stun;
+mod supervisor;
+#[cfg(unix)]
+mod systemd;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod tunnel;
+mod upnp;
+mod upstream;
 
 struct LocalTime;
 
@@ -18,30 +61,271 @@ impl FormatTime for LocalTime {
 }
 
 pub static WAN_ADDR: OnceCell<RwLock<SocketAddr>> = OnceCell::const_new();
+pub static WAN_ADDR6: RwLock<Option<std::net::Ipv6Addr>> = RwLock::const_new(None);
+pub static LAST_DDNS_UPDATE: RwLock<Option<DateTime<Local>>> = RwLock::const_new(None);
+pub static LAST_STUN_SUCCESS: RwLock<Option<DateTime<Local>>> = RwLock::const_new(None);
+pub static NAT_BEHAVIOR: RwLock<Option<stun::NatBehavior>> = RwLock::const_new(None);
+pub static LISTENER_READY: AtomicBool = AtomicBool::new(false);
+
+// how long shutdown waits for a DNS change that is already under way
+const SHUTDOWN_UPDATE_GRACE: std::time::Duration = std::time::Duration::from_secs(10);
+
+// the default hook writes straight to stderr and bypasses the log sink
+fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("non-string panic payload");
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+            .unwrap_or_else(|| "unknown location".to_string());
+        let thread = std::thread::current();
+        tracing::error!(
+            thread = thread.name().unwrap_or("unnamed"),
+            location = %location,
+            "Panicked: {}",
+            message
+        );
+    }));
+}
+
+fn main() {
+    let registry = tracing_subscriber::registry();
+    #[cfg(feature = "otel")]
+    let (otel, otel_handle) = telemetry::layer();
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel.with_filter(tracing_subscriber::filter::LevelFilter::INFO));
+    // RUST_LOG wins; otherwise the level comes from the configuration, which is only
+    // read once logging is up
+    let env_filter = std::env::var(EnvFilter::DEFAULT_ENV)
+        .ok()
+        .and_then(|directives| EnvFilter::try_new(directives).ok());
+    let from_env = env_filter.is_some();
+    let (level, level_handle) = tracing_subscriber::reload::Layer::new(
+        env_filter.unwrap_or_else(|| EnvFilter::new("info")),
+    );
+    // spans exist for the trace exporter, keep them out of the log lines
+    registry
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_timer(LocalTime)
+                .with_filter(filter_fn(|meta| meta.is_event()).and(level)),
+        )
+        .init();
+    install_panic_hook();
+    let args = cli::Args::parse().unwrap_or_else(|e| {
+        tracing::error!(error = %e, "Invalid command line");
+        std::process::exit(2);
+    });
+
+    if args.version {
+        println!("{}", build_info::describe());
+        return;
+    }
+
+    tracing::info!(
+        version = build_info::VERSION,
+        commit = build_info::GIT_SHA,
+        built = %build_info::build_date(),
+        "Starting BedrockHole"
+    );
+    metrics::init();
+
+    if let Some(path) = args.gen_config {
+        config::BHConfig::default()
+            .write_to_path(&path)
+            .unwrap_or_else(|e| {
+                tracing::error!(error = %e, path = %path.display(), "Failed to write configuration file");
+                std::process::exit(1);
+            });
+        tracing::info!(path = %path.display(), "Default configuration written");
+        return;
+    }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt().with_timer(LocalTime).init();
     WAN_ADDR
-        .set(RwLock::new(format!("0.0.0.0:0").parse().unwrap()))
+        .set(RwLock::new("0.0.0.0:0".parse().unwrap()))
         .unwrap();
 
-    let config = config::BHConfig::_default_load().unwrap_or_else(|e| {
+    let loaded = match &args.config {
+        Some(source) => config::BHConfig::load(source),
+        None => config::BHConfig::_default_load(),
+    };
+    let source = args
+        .config
+        .unwrap_or_else(|| config::ConfigSource::File("config.json".into()));
+    let config = loaded.unwrap_or_else(|e| {
         tracing::error!(error = %e, "Failed to load configuration file");
         std::process::exit(1);
     });
 
-    ddns::init(config.ddns).unwrap_or_else(|e| {
-        tracing::error!(error = %e, "Failed to initialize DDNS provider");
+    config.validate().unwrap_or_else(|e| {
+        tracing::error!(error = %e, "Invalid configuration");
         std::process::exit(1);
     });
 
-    tracing::info!("Starting Bedrock-Hole core services...");
+    // validate() already rejected malformed directives
+    if !from_env
+        && let Ok(filter) = EnvFilter::try_new(&config.general.log_level)
+        && let Err(e) = level_handle.reload(filter)
+    {
+        tracing::warn!(error = %e, "Failed to apply general.log_level");
+    }
 
-    stun::run(config.general, config.forward.local_port).await;
+    if let Some(endpoint) = &config.general.otlp_endpoint {
+        #[cfg(feature = "otel")]
+        if let Err(e) = telemetry::init(&otel_handle, endpoint) {
+            tracing::error!(error = %e, "Failed to start the trace exporter");
+        }
+        #[cfg(not(feature = "otel"))]
+        tracing::warn!(
+            "general.otlp_endpoint is set to {} but this build lacks the otel feature, traces will not be exported",
+            endpoint
+        );
+    }
 
-    forward::run(config.forward).await.unwrap_or_else(|e| {
-        tracing::error!(error = %e, "Core service execution failed");
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = args.worker_threads.or(config.general.worker_threads) {
+        runtime.worker_threads(threads);
+    }
+    let runtime = runtime.build().unwrap_or_else(|e| {
+        tracing::error!(error = %e, "Failed to start the async runtime");
         std::process::exit(1);
     });
+
+    runtime.block_on(run(config, source, args.dry_run));
+    #[cfg(feature = "otel")]
+    telemetry::shutdown();
+}
+
+async fn run(config: config::BHConfig, source: config::ConfigSource, dry_run: bool) {
+    let pid_file = config.general.pid_file.clone();
+    if let Some(path) = &pid_file
+        && let Err(e) = pidfile::write(path)
+    {
+        tracing::error!(error = %e, path = %path.display(), "Failed to write PID file");
+        std::process::exit(1);
+    }
+
+    let remove_records = config.services.ddns
+        && config
+            .ddns
+            .as_ref()
+            .is_some_and(|ddns| ddns.remove_on_shutdown);
+    match config.ddns.filter(|_| config.services.ddns) {
+        Some(ddns_config) => {
+            if let Err(e) = ddns::init(
+                ddns_config,
+                config.general.instance_name.as_deref(),
+                dry_run,
+            ) {
+                tracing::error!(
+                    error = %e,
+                    "Failed to initialize DDNS provider, detected addresses will not be published"
+                );
+            }
+        }
+        None => tracing::info!("DDNS disabled, detected addresses will only be logged"),
+    }
+
+    tracing::info!("Starting Bedrock-Hole core services...");
+    heartbeat::init(
+        config.forward.heartbeat_magic.as_deref(),
+        config.forward.heartbeat_secret.as_deref(),
+    );
+
+    #[cfg(unix)]
+    systemd::spawn_watchdog();
+
+    #[cfg(unix)]
+    supervisor::spawn("reload", move || reload::run(source.clone()));
+    #[cfg(not(unix))]
+    let _ = source;
+
+    if let Some(port) = config.general.status_port {
+        let bind = config.general.status_bind;
+        let health = status::HealthCheck {
+            forward: config.services.forward,
+            stun: config.services.stun,
+            window: std::time::Duration::from_secs(config.general.health_window),
+        };
+        supervisor::spawn("status", move || status::run(bind, port, health));
+    }
+
+    if let Some(addr) = config.general.statsd_addr.clone() {
+        let interval = std::time::Duration::from_secs(config.general.statsd_interval);
+        supervisor::spawn("statsd", move || statsd::run(addr.clone(), interval));
+    }
+
+    if config.general.stats_log_interval > 0 {
+        let interval = std::time::Duration::from_secs(config.general.stats_log_interval);
+        supervisor::spawn("stats", move || stats::run(interval));
+    }
+
+    // the worker that discovers and publishes the address, stopped first on shutdown
+    let mut traversal = None;
+    if config.services.stun {
+        let general = config.general;
+        let local_port = config.forward.local_port;
+        traversal = Some(match general.traversal {
+            Traversal::Stun => {
+                let turn = config.turn;
+                let heartbeat_enabled = config.services.forward;
+                supervisor::spawn("stun", move || {
+                    stun::run(general.clone(), turn.clone(), local_port, heartbeat_enabled)
+                })
+            }
+            Traversal::Upnp => {
+                supervisor::spawn("upnp", move || upnp::run(general.clone(), local_port))
+            }
+            Traversal::Natpmp => {
+                supervisor::spawn("natpmp", move || natpmp::run(general.clone(), local_port))
+            }
+        });
+    }
+
+    if config.services.forward {
+        let forward = config.forward;
+        supervisor::spawn("forward", move || forward::run(forward.clone()));
+    } else {
+        tracing::info!("Forwarding disabled, no listeners will be bound");
+        #[cfg(unix)]
+        systemd::notify_ready();
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!(error = %e, "Failed to listen for shutdown signal");
+        std::future::pending::<()>().await;
+    }
+    tracing::info!(
+        "Shutdown signal received, exiting after {}s of uptime",
+        metrics::uptime().as_secs()
+    );
+    // a DNS change in flight may finish, but the worker must not publish again once the
+    // records and mappings below are being removed
+    if let Some(worker) = traversal {
+        let held = tokio::time::timeout(SHUTDOWN_UPDATE_GRACE, ddns::hold_updates()).await;
+        if held.is_err() {
+            tracing::warn!(
+                "DNS update still running after {}s, stopping it",
+                SHUTDOWN_UPDATE_GRACE.as_secs()
+            );
+        }
+        worker.abort();
+        let _ = worker.await;
+    }
+    upnp::remove_mapping().await;
+    natpmp::remove_mapping().await;
+    if remove_records {
+        ddns::remove_records().await;
+    }
+    if let Some(path) = &pid_file {
+        pidfile::remove(path);
+    }
+    #[cfg(unix)]
+    systemd::notify_stopping();
 }
@@ -0,0 +1,294 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+
+use crate::{LAST_DDNS_UPDATE, LAST_STUN_SUCCESS, stun::StunError, upstream};
+
+// monotonic for the uptime, wall clock to report when the process started
+static STARTED: LazyLock<(Instant, DateTime<Local>)> =
+    LazyLock::new(|| (Instant::now(), Local::now()));
+
+pub struct Metrics {
+    pub active_connections: AtomicU64,
+    pub sessions_total: AtomicU64,
+    // counted once a session ends, up is client to upstream
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    pub rejected_connections: AtomicU64,
+    pub heartbeat_success: AtomicU64,
+    pub heartbeat_failures: AtomicU64,
+    pub heartbeat_rtt_us: AtomicU64,
+    pub stun_rtt_us: AtomicU64,
+    pub ddns_updates: AtomicU64,
+    pub ddns_failures: AtomicU64,
+    pub wan_address_changes: AtomicU64,
+    pub stun_errors: [AtomicU64; StunError::KINDS.len()],
+    // only filled while a GeoIP database is configured
+    pub connections_by_country: Mutex<BTreeMap<String, u64>>,
+}
+
+pub static METRICS: Metrics = Metrics {
+    active_connections: AtomicU64::new(0),
+    sessions_total: AtomicU64::new(0),
+    bytes_up: AtomicU64::new(0),
+    bytes_down: AtomicU64::new(0),
+    rejected_connections: AtomicU64::new(0),
+    heartbeat_success: AtomicU64::new(0),
+    heartbeat_failures: AtomicU64::new(0),
+    heartbeat_rtt_us: AtomicU64::new(0),
+    stun_rtt_us: AtomicU64::new(0),
+    ddns_updates: AtomicU64::new(0),
+    ddns_failures: AtomicU64::new(0),
+    wan_address_changes: AtomicU64::new(0),
+    stun_errors: [const { AtomicU64::new(0) }; StunError::KINDS.len()],
+    connections_by_country: Mutex::new(BTreeMap::new()),
+};
+
+pub fn init() {
+    LazyLock::force(&STARTED);
+}
+
+pub fn uptime() -> Duration {
+    STARTED.0.elapsed()
+}
+
+pub fn start_time() -> DateTime<Local> {
+    STARTED.1
+}
+
+pub fn count_country(country: Option<&str>) {
+    let mut counts = METRICS.connections_by_country.lock().unwrap();
+    *counts
+        .entry(country.unwrap_or("unknown").to_owned())
+        .or_insert(0) += 1;
+}
+
+pub struct ConnectionGuard;
+
+impl ConnectionGuard {
+    pub fn new() -> Self {
+        METRICS.active_connections.fetch_add(1, Ordering::Relaxed);
+        METRICS.sessions_total.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        METRICS.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    // a latest measurement, a gauge to Prometheus and a timing to StatsD
+    Timer,
+}
+
+// one exported value; shared by the Prometheus endpoint and the StatsD exporter
+pub struct Sample {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub help: &'static str,
+    pub label: Option<(&'static str, String)>,
+    pub value: f64,
+}
+
+fn sample(name: &'static str, kind: Kind, help: &'static str, value: f64) -> Sample {
+    Sample {
+        name,
+        kind,
+        help,
+        label: None,
+        value,
+    }
+}
+
+fn upstream_samples<F>(out: &mut Vec<Sample>, name: &'static str, help: &'static str, value: F)
+where
+    F: Fn(&upstream::Upstream) -> f64,
+{
+    for u in upstream::registered() {
+        out.push(Sample {
+            name,
+            kind: Kind::Gauge,
+            help,
+            label: Some(("upstream", u.name())),
+            value: value(&u),
+        });
+    }
+}
+
+fn timestamp(ts: DateTime<Local>) -> f64 {
+    ts.timestamp_millis() as f64 / 1000.0
+}
+
+pub async fn samples() -> Vec<Sample> {
+    let mut out = vec![
+        sample(
+            "bedrockhole_uptime_seconds",
+            Kind::Gauge,
+            "Seconds since the process started",
+            uptime().as_secs_f64(),
+        ),
+        sample(
+            "bedrockhole_start_time_seconds",
+            Kind::Gauge,
+            "Unix time the process started at",
+            timestamp(start_time()),
+        ),
+        sample(
+            "bedrockhole_active_connections",
+            Kind::Gauge,
+            "Proxy sessions currently being forwarded",
+            METRICS.active_connections.load(Ordering::Relaxed) as f64,
+        ),
+        sample(
+            "bedrockhole_sessions_total",
+            Kind::Counter,
+            "Proxy sessions started since the process began",
+            METRICS.sessions_total.load(Ordering::Relaxed) as f64,
+        ),
+        sample(
+            "bedrockhole_rejected_connections_total",
+            Kind::Counter,
+            "Connections closed right after accept because a limit was exceeded",
+            METRICS.rejected_connections.load(Ordering::Relaxed) as f64,
+        ),
+        sample(
+            "bedrockhole_heartbeat_success_total",
+            Kind::Counter,
+            "Heartbeats answered through the public address",
+            METRICS.heartbeat_success.load(Ordering::Relaxed) as f64,
+        ),
+        sample(
+            "bedrockhole_heartbeat_failures_total",
+            Kind::Counter,
+            "Heartbeats that failed, i.e. the public address was not reachable",
+            METRICS.heartbeat_failures.load(Ordering::Relaxed) as f64,
+        ),
+        sample(
+            "bedrockhole_heartbeat_rtt_seconds",
+            Kind::Timer,
+            "Round-trip time of the last successful heartbeat",
+            METRICS.heartbeat_rtt_us.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        ),
+        sample(
+            "bedrockhole_stun_rtt_seconds",
+            Kind::Timer,
+            "Round-trip time of the STUN binding request whose mapping was used last",
+            METRICS.stun_rtt_us.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        ),
+        sample(
+            "bedrockhole_ddns_updates_total",
+            Kind::Counter,
+            "DNS updates confirmed by the provider",
+            METRICS.ddns_updates.load(Ordering::Relaxed) as f64,
+        ),
+        sample(
+            "bedrockhole_ddns_failures_total",
+            Kind::Counter,
+            "Failed DNS update attempts, including ones that were retried",
+            METRICS.ddns_failures.load(Ordering::Relaxed) as f64,
+        ),
+        sample(
+            "bedrockhole_wan_address_changes_total",
+            Kind::Counter,
+            "Times STUN reported a different public address than the previous query",
+            METRICS.wan_address_changes.load(Ordering::Relaxed) as f64,
+        ),
+    ];
+    // absent until the first success, so a stale zero never looks like a recent update
+    if let Some(ts) = *LAST_STUN_SUCCESS.read().await {
+        out.push(sample(
+            "bedrockhole_last_mapping_timestamp_seconds",
+            Kind::Gauge,
+            "Unix time the public mapping was last confirmed",
+            timestamp(ts),
+        ));
+    }
+    if let Some(ts) = *LAST_DDNS_UPDATE.read().await {
+        out.push(sample(
+            "bedrockhole_last_ddns_update_timestamp_seconds",
+            Kind::Gauge,
+            "Unix time of the last confirmed DNS update",
+            timestamp(ts),
+        ));
+    }
+    for (kind, count) in StunError::KINDS.iter().zip(&METRICS.stun_errors) {
+        out.push(Sample {
+            name: "bedrockhole_stun_errors_total",
+            kind: Kind::Counter,
+            help: "Failed STUN binding requests by cause",
+            label: Some(("kind", kind.to_string())),
+            value: count.load(Ordering::Relaxed) as f64,
+        });
+    }
+    for (direction, count) in [("up", &METRICS.bytes_up), ("down", &METRICS.bytes_down)] {
+        out.push(Sample {
+            name: "bedrockhole_forwarded_bytes_total",
+            kind: Kind::Counter,
+            help: "Bytes relayed by finished sessions, up is from the client to the upstream",
+            label: Some(("direction", direction.to_string())),
+            value: count.load(Ordering::Relaxed) as f64,
+        });
+    }
+    for (country, count) in METRICS.connections_by_country.lock().unwrap().iter() {
+        out.push(Sample {
+            name: "bedrockhole_connections_total",
+            kind: Kind::Counter,
+            help: "Accepted connections by GeoIP country of the source address",
+            label: Some(("country", country.clone())),
+            value: *count as f64,
+        });
+    }
+    upstream_samples(
+        &mut out,
+        "bedrockhole_upstream_healthy",
+        "Whether the upstream is currently considered healthy",
+        |u| u.is_healthy() as u8 as f64,
+    );
+    upstream_samples(
+        &mut out,
+        "bedrockhole_upstream_active_connections",
+        "Proxy sessions currently forwarded to the upstream",
+        |u| u.active_connections() as f64,
+    );
+    out
+}
+
+pub async fn render() -> String {
+    let mut out = String::new();
+    let mut last_name = "";
+    for s in samples().await {
+        // labelled series of one metric share a single HELP/TYPE header
+        if s.name != last_name {
+            let kind = match s.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge | Kind::Timer => "gauge",
+            };
+            let _ = writeln!(out, "# HELP {} {}", s.name, s.help);
+            let _ = writeln!(out, "# TYPE {} {}", s.name, kind);
+            last_name = s.name;
+        }
+        match &s.label {
+            Some((key, value)) => {
+                let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", s.name, key, value, s.value);
+            }
+            None => {
+                let _ = writeln!(out, "{} {}", s.name, s.value);
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,596 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    },
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::{Semaphore, mpsc},
+};
+
+use crate::{
+    config::ForwardConfig,
+    sockopt, tunnel,
+    upstream::{UpstreamLease, UpstreamPool},
+};
+
+// frame: kind (1), stream id (4), payload length (2), payload
+const HEADER_LEN: usize = 7;
+const MAX_DATA: usize = 16 * 1024;
+// bytes a stream may have in flight before the receiver grants more
+const INITIAL_WINDOW: u32 = 256 * 1024;
+const WINDOW_UPDATE_THRESHOLD: u32 = INITIAL_WINDOW / 4;
+const FRAME_QUEUE: usize = 64;
+const CARRIER_BUFFER: usize = 256 * 1024;
+
+const SYN: u8 = 0;
+const DATA: u8 = 1;
+const FIN: u8 = 2;
+const RST: u8 = 3;
+const WINDOW: u8 = 4;
+
+struct Frame {
+    kind: u8,
+    id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.push(self.kind);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&(self.payload.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+}
+
+enum Incoming {
+    Data(Vec<u8>),
+    Reset,
+}
+
+struct StreamEntry {
+    // dropped on FIN, so the receiving side drains what is queued and then sees EOF
+    incoming: Option<mpsc::UnboundedSender<Incoming>>,
+    credit: Arc<Semaphore>,
+    // what the peer may still send: the initial window plus granted credit, less what arrived
+    window: u32,
+}
+
+#[derive(Default)]
+struct Shared {
+    streams: Mutex<HashMap<u32, StreamEntry>>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn register(self: &Arc<Self>, id: u32, frames: &mpsc::Sender<Frame>) -> io::Result<MuxStream> {
+        // the queue is unbounded, but read_loop resets a stream whose peer overruns the window
+        let (tx, rx) = mpsc::unbounded_channel();
+        let credit = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+        match self.streams.lock().unwrap().entry(id) {
+            Entry::Occupied(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Stream id {} is already open", id),
+                ));
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(StreamEntry {
+                    incoming: Some(tx),
+                    credit: credit.clone(),
+                    window: INITIAL_WINDOW,
+                });
+            }
+        }
+
+        Ok(MuxStream {
+            id,
+            shared: self.clone(),
+            frames: frames.clone(),
+            incoming: rx,
+            credit,
+            clean: false,
+        })
+    }
+
+    fn reset(&self, id: u32) {
+        if let Some(entry) = self.streams.lock().unwrap().remove(&id) {
+            if let Some(tx) = entry.incoming {
+                let _ = tx.send(Incoming::Reset);
+            }
+            entry.credit.close();
+        }
+    }
+
+    // called before the window update goes out, so the peer never outruns the count
+    fn grant(&self, id: u32, n: u32) {
+        if let Some(entry) = self.streams.lock().unwrap().get_mut(&id) {
+            entry.window += n;
+        }
+    }
+
+    // the carrier is gone: every stream on it is reset
+    fn teardown(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        for (_, entry) in self.streams.lock().unwrap().drain() {
+            if let Some(tx) = entry.incoming {
+                let _ = tx.send(Incoming::Reset);
+            }
+            entry.credit.close();
+        }
+    }
+}
+
+// many sessions over one connection between two instances, smux style
+pub struct Mux {
+    shared: Arc<Shared>,
+    frames: mpsc::Sender<Frame>,
+    next_id: AtomicU32,
+}
+
+impl Mux {
+    // with accept set, streams opened by the peer are handed over together with the
+    // client address they carry; the opening side passes None
+    fn new<S>(io: S, accept: Option<mpsc::Sender<(MuxStream, Option<SocketAddr>)>>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let (frames, queue) = mpsc::channel(FRAME_QUEUE);
+        let (reader, writer) = tokio::io::split(io);
+
+        let write_shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = write_loop(writer, queue).await {
+                tracing::debug!("Multiplexed tunnel write failed: {}", e);
+            }
+            write_shared.teardown();
+        });
+
+        let read_shared = shared.clone();
+        let read_frames = frames.clone();
+        tokio::spawn(async move {
+            match read_loop(reader, &read_shared, &read_frames, accept).await {
+                Ok(()) => tracing::info!("Multiplexed tunnel closed"),
+                Err(e) => tracing::warn!("Multiplexed tunnel lost: {}", e),
+            }
+            read_shared.teardown();
+        });
+
+        Self {
+            shared,
+            frames,
+            next_id: AtomicU32::new(1),
+        }
+    }
+
+    // runs over the tunnel session when there is one, straight over the socket otherwise;
+    // the lease, if any, is held until the carrier closes
+    pub fn start(
+        mut carrier: TcpStream,
+        session: Option<tunnel::Session>,
+        accept: Option<mpsc::Sender<(MuxStream, Option<SocketAddr>)>>,
+        lease: Option<UpstreamLease>,
+    ) -> Self {
+        let (local, mut remote) = tokio::io::duplex(CARRIER_BUFFER);
+        tokio::spawn(async move {
+            let _lease = lease;
+            let res = match session {
+                Some(session) => {
+                    let (sent, received) = (AtomicU64::default(), AtomicU64::default());
+                    tunnel::carry(&mut remote, &mut carrier, session, &sent, &received).await
+                }
+                None => tokio::io::copy_bidirectional(&mut remote, &mut carrier)
+                    .await
+                    .map(|_| ()),
+            };
+            if let Err(e) = res {
+                tracing::debug!("Multiplexed tunnel carrier ended: {}", e);
+            }
+        });
+
+        Self::new(local, accept)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn active_streams(&self) -> usize {
+        self.shared.streams.lock().unwrap().len()
+    }
+
+    pub async fn open(&self, client: SocketAddr) -> io::Result<MuxStream> {
+        if self.is_closed() {
+            return Err(closed_error());
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stream = self.shared.register(id, &self.frames)?;
+        self.frames
+            .send(Frame {
+                kind: SYN,
+                id,
+                payload: client.to_string().into_bytes(),
+            })
+            .await
+            .map_err(|_| closed_error())?;
+
+        Ok(stream)
+    }
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Multiplexed tunnel is closed")
+}
+
+fn reset_error() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "Stream reset by the peer")
+}
+
+async fn write_loop<W>(mut writer: W, mut queue: mpsc::Receiver<Frame>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(frame) = queue.recv().await {
+        writer.write_all(&frame.encode()).await?;
+    }
+
+    writer.shutdown().await
+}
+
+async fn read_loop<R>(
+    mut reader: R,
+    shared: &Arc<Shared>,
+    frames: &mpsc::Sender<Frame>,
+    accept: Option<mpsc::Sender<(MuxStream, Option<SocketAddr>)>>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; HEADER_LEN];
+    loop {
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+        let len = u16::from_be_bytes(header[5..7].try_into().unwrap()) as usize;
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+
+        match header[0] {
+            SYN => {
+                let Some(accept) = &accept else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Peer opened a stream on an outgoing tunnel",
+                    ));
+                };
+                let client = std::str::from_utf8(&payload)
+                    .ok()
+                    .and_then(|addr| addr.parse().ok());
+                // a reused id would orphan the open stream, the peer is not to be trusted further
+                let stream = shared.register(id, frames)?;
+                if accept.send((stream, client)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            DATA => {
+                let overrun = match shared.streams.lock().unwrap().get_mut(&id) {
+                    Some(entry) if payload.len() as u32 > entry.window => true,
+                    Some(entry) => {
+                        entry.window -= payload.len() as u32;
+                        if let Some(tx) = &entry.incoming {
+                            let _ = tx.send(Incoming::Data(payload));
+                        }
+                        false
+                    }
+                    None => false,
+                };
+                if overrun {
+                    tracing::warn!(
+                        "Multiplexed stream {} overran its receive window, resetting it",
+                        id
+                    );
+                    shared.reset(id);
+                    frames
+                        .send(Frame {
+                            kind: RST,
+                            id,
+                            payload: Vec::new(),
+                        })
+                        .await
+                        .map_err(|_| closed_error())?;
+                }
+            }
+            FIN => {
+                if let Some(entry) = shared.streams.lock().unwrap().get_mut(&id) {
+                    entry.incoming = None;
+                }
+            }
+            RST => shared.reset(id),
+            WINDOW => {
+                let granted = payload
+                    .try_into()
+                    .map(u32::from_be_bytes)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Bad window update"))?;
+                if let Some(entry) = shared.streams.lock().unwrap().get(&id) {
+                    entry.credit.add_permits(granted as usize);
+                }
+            }
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown frame type {}", kind),
+                ));
+            }
+        }
+    }
+}
+
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+    frames: mpsc::Sender<Frame>,
+    incoming: mpsc::UnboundedReceiver<Incoming>,
+    credit: Arc<Semaphore>,
+    // both directions finished with a FIN, nothing to reset
+    clean: bool,
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.shared.streams.lock().unwrap().remove(&self.id);
+        if !self.clean {
+            let _ = self.frames.try_send(Frame {
+                kind: RST,
+                id: self.id,
+                payload: Vec::new(),
+            });
+        }
+    }
+}
+
+// relays a socket over a stream, counting the bytes sent into and received from the tunnel
+pub async fn pump(
+    socket: &mut TcpStream,
+    mut stream: MuxStream,
+    sent: &AtomicU64,
+    received: &AtomicU64,
+) -> io::Result<()> {
+    let (mut socket_read, mut socket_write) = socket.split();
+    let id = stream.id;
+    let frames = stream.frames.clone();
+    let shared = stream.shared.clone();
+    let credit = stream.credit.clone();
+    let incoming = &mut stream.incoming;
+
+    let upload = async {
+        let mut buf = vec![0u8; MAX_DATA];
+        loop {
+            let n = socket_read.read(&mut buf).await?;
+            if n == 0 {
+                frames
+                    .send(Frame {
+                        kind: FIN,
+                        id,
+                        payload: Vec::new(),
+                    })
+                    .await
+                    .map_err(|_| closed_error())?;
+                return Ok(());
+            }
+
+            // waits for the peer to catch up instead of queueing without bound
+            credit
+                .acquire_many(n as u32)
+                .await
+                .map_err(|_| reset_error())?
+                .forget();
+            frames
+                .send(Frame {
+                    kind: DATA,
+                    id,
+                    payload: buf[..n].to_vec(),
+                })
+                .await
+                .map_err(|_| closed_error())?;
+            sent.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    };
+
+    let download = async {
+        let mut consumed = 0;
+        loop {
+            match incoming.recv().await {
+                Some(Incoming::Data(data)) => {
+                    socket_write.write_all(&data).await?;
+                    received.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    consumed += data.len() as u32;
+                    if consumed >= WINDOW_UPDATE_THRESHOLD {
+                        shared.grant(id, consumed);
+                        frames
+                            .send(Frame {
+                                kind: WINDOW,
+                                id,
+                                payload: consumed.to_be_bytes().to_vec(),
+                            })
+                            .await
+                            .map_err(|_| closed_error())?;
+                        consumed = 0;
+                    }
+                }
+                Some(Incoming::Reset) => return Err(reset_error()),
+                None => match socket_write.shutdown().await {
+                    Err(e) if e.kind() != io::ErrorKind::NotConnected => return Err(e),
+                    _ => return Ok(()),
+                },
+            }
+        }
+    };
+
+    let res = tokio::try_join!(upload, download);
+    stream.clean = res.is_ok();
+    res.map(|_| ())
+}
+
+// the edge's long-lived carriers to the home instance, opened on demand up to size
+pub struct MuxPool {
+    size: usize,
+    carriers: tokio::sync::Mutex<Vec<Arc<Mux>>>,
+}
+
+impl MuxPool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            carriers: tokio::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    pub async fn open(
+        &self,
+        client: SocketAddr,
+        pool: &UpstreamPool,
+        config: &ForwardConfig,
+    ) -> anyhow::Result<MuxStream> {
+        let mut carriers = self.carriers.lock().await;
+        carriers.retain(|mux| !mux.is_closed());
+        let mux = match carriers.iter().min_by_key(|mux| mux.active_streams()) {
+            Some(mux) if carriers.len() >= self.size => mux.clone(),
+            _ => {
+                let mux = Arc::new(dial(pool, config).await?);
+                carriers.push(mux.clone());
+                mux
+            }
+        };
+        drop(carriers);
+
+        Ok(mux.open(client).await?)
+    }
+}
+
+async fn dial(pool: &UpstreamPool, config: &ForwardConfig) -> anyhow::Result<Mux> {
+    let (stream, lease) = pool.connect(config).await?;
+    let mut stream = stream.into_tcp()?;
+    if let Err(e) = sockopt::apply_keepalive(&stream, &config.keepalive) {
+        tracing::warn!("Failed to configure TCP keepalive: {}", e);
+    }
+    let session = match &config.tunnel {
+        Some(tunnel) => tunnel::establish(&mut stream, tunnel).await?,
+        None => None,
+    };
+    tracing::info!(
+        "Opened multiplexed tunnel to upstream {}",
+        lease.upstream().name()
+    );
+
+    Ok(Mux::start(stream, session, None, Some(lease)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    // a mux accepting streams, and the raw end of its carrier standing in for the peer
+    fn accepting() -> (
+        Mux,
+        mpsc::Receiver<(MuxStream, Option<SocketAddr>)>,
+        DuplexStream,
+    ) {
+        let (local, peer) = tokio::io::duplex(CARRIER_BUFFER);
+        let (accept, accepted) = mpsc::channel(4);
+        (Mux::new(local, Some(accept)), accepted, peer)
+    }
+
+    async fn send(peer: &mut DuplexStream, kind: u8, id: u32, payload: &[u8]) {
+        let frame = Frame {
+            kind,
+            id,
+            payload: payload.to_vec(),
+        };
+        peer.write_all(&frame.encode()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn peer_overrunning_the_window_gets_the_stream_reset() {
+        let (_mux, mut accepted, mut peer) = accepting();
+        send(&mut peer, SYN, 1, b"127.0.0.1:1").await;
+        let (mut stream, client) = accepted.recv().await.unwrap();
+        assert_eq!(client, Some("127.0.0.1:1".parse().unwrap()));
+
+        // a full window is allowed without any credit granted, one byte more is not
+        let chunk = vec![0u8; MAX_DATA];
+        for _ in 0..INITIAL_WINDOW as usize / MAX_DATA {
+            send(&mut peer, DATA, 1, &chunk).await;
+        }
+        send(&mut peer, DATA, 1, b"x").await;
+
+        let mut header = [0u8; HEADER_LEN];
+        peer.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], RST);
+        assert_eq!(u32::from_be_bytes(header[1..5].try_into().unwrap()), 1);
+
+        let mut queued = 0;
+        loop {
+            match stream.incoming.recv().await {
+                Some(Incoming::Data(data)) => queued += data.len(),
+                Some(Incoming::Reset) => break,
+                None => panic!("the stream ended without a reset"),
+            }
+        }
+        assert_eq!(queued, INITIAL_WINDOW as usize);
+    }
+
+    #[tokio::test]
+    async fn granted_credit_extends_the_window() {
+        let (_mux, mut accepted, mut peer) = accepting();
+        send(&mut peer, SYN, 1, b"").await;
+        let (mut stream, _) = accepted.recv().await.unwrap();
+
+        let chunk = vec![0u8; MAX_DATA];
+        for _ in 0..INITIAL_WINDOW as usize / MAX_DATA {
+            send(&mut peer, DATA, 1, &chunk).await;
+        }
+        for _ in 0..INITIAL_WINDOW as usize / MAX_DATA {
+            assert!(matches!(
+                stream.incoming.recv().await,
+                Some(Incoming::Data(_))
+            ));
+        }
+        stream.shared.grant(1, MAX_DATA as u32);
+        send(&mut peer, DATA, 1, &chunk).await;
+        assert!(matches!(
+            stream.incoming.recv().await,
+            Some(Incoming::Data(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reused_stream_id_fails_the_carrier() {
+        let (mux, mut accepted, mut peer) = accepting();
+        send(&mut peer, SYN, 7, b"").await;
+        let (mut stream, _) = accepted.recv().await.unwrap();
+        send(&mut peer, SYN, 7, b"").await;
+
+        assert!(matches!(
+            stream.incoming.recv().await,
+            Some(Incoming::Reset)
+        ));
+        assert!(mux.is_closed());
+        assert!(accepted.recv().await.is_none());
+    }
+}
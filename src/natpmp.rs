@@ -0,0 +1,268 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, bail};
+use chrono::Local;
+use tokio::{net::UdpSocket, sync::Mutex};
+
+use crate::{LAST_STUN_SUCCESS, config::GeneralConfig, ddns, random, reachability};
+
+const SERVER_PORT: u16 = 5351;
+const RENEW_INTERVAL: u64 = 60;
+const PROTO_TCP: u8 = 6;
+
+const NATPMP_VERSION: u8 = 0;
+const NATPMP_OP_EXTERNAL_ADDRESS: u8 = 0;
+const NATPMP_OP_MAP_TCP: u8 = 2;
+
+const PCP_VERSION: u8 = 2;
+const PCP_OP_MAP: u8 = 1;
+const PCP_UNSUPP_VERSION: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Pcp,
+    NatPmp,
+}
+
+struct Mapping {
+    protocol: Protocol,
+    external: SocketAddr,
+    lifetime: u32,
+}
+
+struct ActiveMapping {
+    gateway: Ipv4Addr,
+    protocol: Protocol,
+    nonce: [u8; 12],
+    port: u16,
+}
+
+static MAPPING: Mutex<Option<ActiveMapping>> = Mutex::const_new(None);
+
+#[cfg(target_os = "linux")]
+fn default_gateway() -> anyhow::Result<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    for line in routes.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [_, "00000000", gateway, ..] = fields.as_slice() {
+            // the kernel prints the network-order address as a native-endian integer
+            return Ok(Ipv4Addr::from(
+                u32::from_str_radix(gateway, 16)?.to_ne_bytes(),
+            ));
+        }
+    }
+    bail!("No default route found")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> anyhow::Result<Ipv4Addr> {
+    bail!("Cannot detect the default gateway on this platform, set general.gateway")
+}
+
+async fn transact(socket: &UdpSocket, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+    // RFC 6886 retransmission schedule, starting at 250ms and doubling
+    let mut wait = std::time::Duration::from_millis(250);
+    for _ in 0..4 {
+        socket.send(request).await?;
+
+        let mut buf = [0u8; 1100];
+        if let Ok(res) = tokio::time::timeout(wait, socket.recv(&mut buf)).await {
+            let n = res?;
+            return Ok(buf[..n].to_vec());
+        }
+        wait *= 2;
+    }
+    bail!("Gateway did not answer the port mapping request")
+}
+
+fn pcp_map_request(client: Ipv4Addr, nonce: [u8; 12], port: u16, lifetime: u32) -> Vec<u8> {
+    let mut req = Vec::with_capacity(60);
+    req.extend_from_slice(&[PCP_VERSION, PCP_OP_MAP, 0, 0]);
+    req.extend_from_slice(&lifetime.to_be_bytes());
+    req.extend_from_slice(&client.to_ipv6_mapped().octets());
+    req.extend_from_slice(&nonce);
+    req.extend_from_slice(&[PROTO_TCP, 0, 0, 0]);
+    req.extend_from_slice(&port.to_be_bytes());
+    req.extend_from_slice(&port.to_be_bytes());
+    req.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    req
+}
+
+async fn map_pcp(
+    socket: &UdpSocket,
+    client: Ipv4Addr,
+    nonce: [u8; 12],
+    port: u16,
+    lifetime: u32,
+) -> anyhow::Result<Option<Mapping>> {
+    let resp = transact(socket, &pcp_map_request(client, nonce, port, lifetime)).await?;
+
+    // NAT-PMP only gateways answer a PCP request with their own version
+    if resp.len() >= 4 && resp[0] == NATPMP_VERSION {
+        return Ok(None);
+    }
+    if resp.len() < 60 || resp[1] != PCP_OP_MAP | 0x80 || resp[24..36] != nonce {
+        bail!("Malformed PCP MAP response");
+    }
+    match resp[3] {
+        0 => {}
+        PCP_UNSUPP_VERSION => return Ok(None),
+        code => bail!("PCP MAP request failed with result code {}", code),
+    }
+
+    let lifetime = u32::from_be_bytes([resp[4], resp[5], resp[6], resp[7]]);
+    let external_port = u16::from_be_bytes([resp[42], resp[43]]);
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&resp[44..60]);
+    let external_ip = Ipv6Addr::from(octets).to_canonical();
+
+    Ok(Some(Mapping {
+        protocol: Protocol::Pcp,
+        external: SocketAddr::new(external_ip, external_port),
+        lifetime,
+    }))
+}
+
+async fn map_natpmp(socket: &UdpSocket, port: u16, lifetime: u32) -> anyhow::Result<Mapping> {
+    let resp = transact(socket, &[NATPMP_VERSION, NATPMP_OP_EXTERNAL_ADDRESS]).await?;
+    if resp.len() < 12 || resp[1] != NATPMP_OP_EXTERNAL_ADDRESS | 0x80 {
+        bail!("Malformed NAT-PMP external address response");
+    }
+    let code = u16::from_be_bytes([resp[2], resp[3]]);
+    if code != 0 {
+        bail!(
+            "NAT-PMP external address request failed with result code {}",
+            code
+        );
+    }
+    let external_ip = Ipv4Addr::new(resp[8], resp[9], resp[10], resp[11]);
+
+    let mut req = vec![NATPMP_VERSION, NATPMP_OP_MAP_TCP, 0, 0];
+    req.extend_from_slice(&port.to_be_bytes());
+    req.extend_from_slice(&port.to_be_bytes());
+    req.extend_from_slice(&lifetime.to_be_bytes());
+
+    let resp = transact(socket, &req).await?;
+    if resp.len() < 16 || resp[1] != NATPMP_OP_MAP_TCP | 0x80 {
+        bail!("Malformed NAT-PMP mapping response");
+    }
+    let code = u16::from_be_bytes([resp[2], resp[3]]);
+    if code != 0 {
+        bail!("NAT-PMP mapping request failed with result code {}", code);
+    }
+
+    Ok(Mapping {
+        protocol: Protocol::NatPmp,
+        external: SocketAddr::new(
+            IpAddr::V4(external_ip),
+            u16::from_be_bytes([resp[10], resp[11]]),
+        ),
+        lifetime: u32::from_be_bytes([resp[12], resp[13], resp[14], resp[15]]),
+    })
+}
+
+async fn connect(gateway: Ipv4Addr) -> anyhow::Result<(UdpSocket, Ipv4Addr)> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect((gateway, SERVER_PORT)).await?;
+    let client = match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => bail!("Gateway is not reachable over IPv4"),
+    };
+    Ok((socket, client))
+}
+
+pub async fn run(config: GeneralConfig, local_port: u16) -> anyhow::Result<()> {
+    let gateway = match config.gateway {
+        Some(gateway) => gateway,
+        None => default_gateway().map_err(|e| anyhow!("Gateway detection failed: {}", e))?,
+    };
+    let (socket, client) = connect(gateway).await?;
+    tracing::info!("Requesting port mappings from gateway {}", gateway);
+
+    let nonce = random::bytes();
+    let mut protocol = Protocol::Pcp;
+    let mut last_addr = ddns::last_published(&config).await;
+
+    loop {
+        let mapping = match protocol {
+            Protocol::Pcp => {
+                match map_pcp(&socket, client, nonce, local_port, config.mapping_lease).await? {
+                    Some(mapping) => mapping,
+                    None => {
+                        tracing::info!("Gateway does not speak PCP, falling back to NAT-PMP");
+                        protocol = Protocol::NatPmp;
+                        continue;
+                    }
+                }
+            }
+            Protocol::NatPmp => map_natpmp(&socket, local_port, config.mapping_lease).await?,
+        };
+
+        *MAPPING.lock().await = Some(ActiveMapping {
+            gateway,
+            protocol: mapping.protocol,
+            nonce,
+            port: local_port,
+        });
+        tracing::info!(
+            "{} mapping {} -> {}:{} active for {}s",
+            if mapping.protocol == Protocol::Pcp {
+                "PCP"
+            } else {
+                "NAT-PMP"
+            },
+            mapping.external,
+            client,
+            local_port,
+            mapping.lifetime
+        );
+        *LAST_STUN_SUCCESS.write().await = Some(Local::now());
+
+        if ddns::publish(&config.state_file, mapping.external, last_addr).await {
+            if last_addr != Some(mapping.external) {
+                reachability::spawn_check(&config, mapping.external);
+            }
+            last_addr = Some(mapping.external);
+        }
+
+        // renewing often also notices a changed external IP without waiting for the lease
+        let renew = (mapping.lifetime as u64 / 2).clamp(1, RENEW_INTERVAL);
+        tokio::time::sleep(std::time::Duration::from_secs(renew)).await;
+    }
+}
+
+pub async fn remove_mapping() {
+    let Some(ActiveMapping {
+        gateway,
+        protocol,
+        nonce,
+        port,
+    }) = MAPPING.lock().await.take()
+    else {
+        return;
+    };
+
+    let res = async {
+        let (socket, client) = connect(gateway).await?;
+        match protocol {
+            Protocol::Pcp => {
+                socket
+                    .send(&pcp_map_request(client, nonce, port, 0))
+                    .await?;
+            }
+            Protocol::NatPmp => {
+                let mut req = vec![NATPMP_VERSION, NATPMP_OP_MAP_TCP, 0, 0];
+                req.extend_from_slice(&port.to_be_bytes());
+                req.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+                socket.send(&req).await?;
+            }
+        }
+        Ok::<(), anyhow::Error>(())
+    }
+    .await;
+
+    match res {
+        Ok(()) => tracing::info!("Removed port mapping for port {}", port),
+        Err(e) => tracing::warn!("Failed to remove port mapping for port {}: {}", port, e),
+    }
+}
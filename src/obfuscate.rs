@@ -0,0 +1,24 @@
+// not encryption: it only hides the Minecraft protocol from pattern-matching middleboxes
+#[derive(Clone)]
+pub struct XorStream {
+    key: Vec<u8>,
+    pos: u64,
+}
+
+impl XorStream {
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            key: key.to_vec(),
+            pos: 0,
+        }
+    }
+
+    // the key cycles, mixed with the cycle count so repeated plaintext does not repeat with it
+    pub fn apply(&mut self, buf: &mut [u8]) {
+        let len = self.key.len() as u64;
+        for byte in buf {
+            *byte ^= self.key[(self.pos % len) as usize] ^ (self.pos / len) as u8;
+            self.pos += 1;
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use std::{fs, path::Path};
+
+pub fn write(path: &Path) -> anyhow::Result<()> {
+    if let Ok(old) = fs::read_to_string(path) {
+        tracing::warn!(
+            path = %path.display(),
+            pid = old.trim(),
+            "Overwriting stale PID file"
+        );
+    }
+
+    fs::write(path, format!("{}\n", std::process::id()))?;
+
+    Ok(())
+}
+
+pub fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        tracing::warn!(path = %path.display(), error = %e, "Failed to remove PID file");
+    }
+}
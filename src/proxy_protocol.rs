@@ -0,0 +1,326 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, bail};
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+use crate::config::HAProxyVersion;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+pub fn encode_v1(src: SocketAddr, dst: SocketAddr) -> anyhow::Result<Vec<u8>> {
+    let header = match (src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            format!(
+                "PROXY TCP4 {} {} {} {}\r\n",
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+        }
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+            format!(
+                "PROXY TCP6 {} {} {} {}\r\n",
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+        }
+        _ => return Err(anyhow!("Mismatched IP families for PROXY v1")),
+    };
+
+    Ok(header.into_bytes())
+}
+
+pub fn encode_v2(src: SocketAddr, dst: SocketAddr) -> anyhow::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(64);
+    header.extend_from_slice(&V2_SIGNATURE);
+
+    match (src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            header.extend_from_slice(&[0x21, 0x11]);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.ip().octets());
+            header.extend_from_slice(&dst.ip().octets());
+            header.extend_from_slice(&src.port().to_be_bytes());
+            header.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+            header.extend_from_slice(&[0x21, 0x21]);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&src.ip().octets());
+            header.extend_from_slice(&dst.ip().octets());
+            header.extend_from_slice(&src.port().to_be_bytes());
+            header.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        _ => return Err(anyhow!("Mismatched IP families for PROXY v2")),
+    }
+
+    Ok(header)
+}
+
+fn normalize(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    let mapped = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+
+    // a dual-stack listener and an upstream of the other family must still share one header
+    let (src, dst) = (canonical(src), canonical(dst));
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (mapped(src), mapped(dst))
+    }
+}
+
+pub fn encode(
+    version: HAProxyVersion,
+    src: SocketAddr,
+    dst: SocketAddr,
+) -> anyhow::Result<Vec<u8>> {
+    let (src, dst) = normalize(src, dst);
+    match version {
+        HAProxyVersion::V1 => encode_v1(src, dst),
+        HAProxyVersion::V2 => encode_v2(src, dst),
+    }
+}
+
+fn parse_v1(line: &str) -> anyhow::Result<Option<SocketAddr>> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            proto @ ("TCP4" | "TCP6"),
+            src,
+            _dst,
+            src_port,
+            _dst_port,
+        ] => {
+            let ip: IpAddr = src.parse()?;
+            if ip.is_ipv4() != (*proto == "TCP4") {
+                bail!("PROXY v1 source address {} does not match {}", ip, proto);
+            }
+            Ok(Some(SocketAddr::new(ip, src_port.parse()?)))
+        }
+        _ => bail!("Malformed PROXY v1 header: {:?}", line),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, body: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 0x2 {
+        bail!("Unsupported PROXY v2 version {:#x}", ver_cmd >> 4);
+    }
+
+    match ver_cmd & 0x0F {
+        // LOCAL: health checks from the proxy itself carry no client address
+        0x0 => return Ok(None),
+        0x1 => {}
+        other => bail!("Unsupported PROXY v2 command {:#x}", other),
+    }
+
+    match family {
+        0x11 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x21 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        0x11 | 0x21 => bail!("Truncated PROXY v2 address block"),
+        _ => Ok(None),
+    }
+}
+
+async fn read_header_inner(stream: &mut TcpStream) -> anyhow::Result<Option<SocketAddr>> {
+    // 12 bytes covers the v2 signature without over-reading the shortest v1 line
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).await?;
+
+    if head == V2_SIGNATURE {
+        let mut rest = [0u8; 4];
+        stream.read_exact(&mut rest).await?;
+        let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+
+        return parse_v2(rest[0], rest[1], &body);
+    }
+
+    if head.starts_with(V1_PREFIX) {
+        let mut line = head.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                bail!("PROXY v1 header exceeds {} bytes", V1_MAX_LEN);
+            }
+            line.push(stream.read_u8().await?);
+        }
+
+        let line = std::str::from_utf8(&line[..line.len() - 2])?;
+        return parse_v1(line);
+    }
+
+    bail!("Expected a PROXY protocol header but none was received")
+}
+
+pub async fn read_header(stream: &mut TcpStream) -> anyhow::Result<Option<SocketAddr>> {
+    tokio::time::timeout(HEADER_TIMEOUT, read_header_inner(stream))
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for PROXY protocol header"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn v1_encodes_tcp4_and_tcp6() {
+        assert_eq!(
+            encode_v1(addr("192.0.2.10:50000"), addr("198.51.100.1:19132")).unwrap(),
+            b"PROXY TCP4 192.0.2.10 198.51.100.1 50000 19132\r\n"
+        );
+        assert_eq!(
+            encode_v1(addr("[2001:db8::10]:50000"), addr("[2001:db8::1]:19132")).unwrap(),
+            b"PROXY TCP6 2001:db8::10 2001:db8::1 50000 19132\r\n"
+        );
+    }
+
+    #[test]
+    fn v2_encodes_tcp4_and_tcp6() {
+        let mut tcp4 = V2_SIGNATURE.to_vec();
+        tcp4.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        tcp4.extend_from_slice(&[192, 0, 2, 10, 198, 51, 100, 1, 0xc3, 0x50, 0x4a, 0xbc]);
+        assert_eq!(
+            encode_v2(addr("192.0.2.10:50000"), addr("198.51.100.1:19132")).unwrap(),
+            tcp4
+        );
+
+        let mut tcp6 = V2_SIGNATURE.to_vec();
+        tcp6.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        tcp6.extend_from_slice(&[
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+        ]);
+        tcp6.extend_from_slice(&[
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        ]);
+        tcp6.extend_from_slice(&[0xc3, 0x50, 0x4a, 0xbc]);
+        assert_eq!(
+            encode_v2(addr("[2001:db8::10]:50000"), addr("[2001:db8::1]:19132")).unwrap(),
+            tcp6
+        );
+    }
+
+    #[test]
+    fn mismatched_families_are_rejected_by_the_encoders() {
+        let (v4, v6) = (addr("192.0.2.10:50000"), addr("[2001:db8::1]:19132"));
+        assert!(encode_v1(v4, v6).is_err());
+        assert!(encode_v2(v4, v6).is_err());
+    }
+
+    #[test]
+    fn mismatched_families_are_normalized_to_v4_mapped() {
+        let (src, dst) = normalize(addr("192.0.2.10:50000"), addr("[2001:db8::1]:19132"));
+        assert_eq!(src, addr("[::ffff:192.0.2.10]:50000"));
+        assert_eq!(dst, addr("[2001:db8::1]:19132"));
+
+        // a mapped client on a dual-stack listener talking to an IPv4 upstream stays TCP4
+        let (src, dst) = normalize(
+            addr("[::ffff:192.0.2.10]:50000"),
+            addr("198.51.100.1:19132"),
+        );
+        assert_eq!(src, addr("192.0.2.10:50000"));
+        assert_eq!(dst, addr("198.51.100.1:19132"));
+
+        let header = encode(
+            HAProxyVersion::V1,
+            addr("192.0.2.10:50000"),
+            addr("[2001:db8::1]:19132"),
+        )
+        .unwrap();
+        assert_eq!(
+            header,
+            b"PROXY TCP6 ::ffff:192.0.2.10 2001:db8::1 50000 19132\r\n"
+        );
+    }
+
+    // an independent parser, so a mistake mirrored in our encoder and reader still shows
+    #[test]
+    fn headers_parse_with_the_proxy_header_crate() {
+        use proxy_header::{ParseConfig, ProxiedAddress, ProxyHeader};
+
+        for version in [HAProxyVersion::V1, HAProxyVersion::V2] {
+            for (src, dst) in [
+                ("192.0.2.10:50000", "198.51.100.1:19132"),
+                ("[2001:db8::10]:50000", "[2001:db8::1]:19132"),
+                ("192.0.2.10:50000", "[2001:db8::1]:19132"),
+            ] {
+                let mut buf = encode(version, addr(src), addr(dst)).unwrap();
+                let header_len = buf.len();
+                buf.extend_from_slice(b"payload");
+
+                let (header, len) = ProxyHeader::parse(&buf, ParseConfig::default()).unwrap();
+                assert_eq!(len, header_len);
+                let (src, dst) = normalize(addr(src), addr(dst));
+                assert_eq!(
+                    header.proxied_address(),
+                    Some(&ProxiedAddress::stream(src, dst))
+                );
+            }
+        }
+    }
+
+    async fn round_trip(header: Vec<u8>) -> Option<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        client.write_all(&header).await.unwrap();
+        // the payload after the header is left for the relay
+        client.write_all(b"payload").await.unwrap();
+
+        let src = read_header(&mut accepted).await.unwrap();
+        let mut rest = [0u8; 7];
+        accepted.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"payload");
+        src
+    }
+
+    #[tokio::test]
+    async fn read_header_recovers_the_encoded_source() {
+        for version in [HAProxyVersion::V1, HAProxyVersion::V2] {
+            for (src, dst) in [
+                ("192.0.2.10:50000", "198.51.100.1:19132"),
+                ("[2001:db8::10]:50000", "[2001:db8::1]:19132"),
+                ("192.0.2.10:50000", "[2001:db8::1]:19132"),
+            ] {
+                let header = encode(version, addr(src), addr(dst)).unwrap();
+                let expected = normalize(addr(src), addr(dst)).0;
+                assert_eq!(round_trip(header).await, Some(expected));
+            }
+        }
+    }
+}
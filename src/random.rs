@@ -0,0 +1,12 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher};
+
+// RandomState is seeded from the OS, which is plenty for nonces and identifiers
+pub fn bytes<const N: usize>() -> [u8; N] {
+    let state = RandomState::new();
+    let mut out = [0u8; N];
+    for (i, chunk) in out.chunks_mut(8).enumerate() {
+        let len = chunk.len();
+        chunk.copy_from_slice(&state.hash_one(i).to_be_bytes()[..len]);
+    }
+    out
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::time::Instant;
+
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// counts live sessions per source address
+#[derive(Clone)]
+pub struct PerIpLimit {
+    max: u32,
+    active: Arc<Mutex<HashMap<IpAddr, u32>>>,
+}
+
+impl PerIpLimit {
+    pub fn new(max: u32) -> Self {
+        Self {
+            max,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // None when ip is already at the cap; the slot is released when the guard drops
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<IpSlot> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+
+        Some(IpSlot {
+            ip,
+            active: self.active.clone(),
+        })
+    }
+}
+
+pub struct IpSlot {
+    ip: IpAddr,
+    active: Arc<Mutex<HashMap<IpAddr, u32>>>,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::{net::SocketAddr, time::Duration};
+
+use crate::{config::GeneralConfig, heartbeat, socks5::Socks5Proxy};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// runs in the background so a slow relay never delays the worker that published addr
+pub fn spawn_check(config: &GeneralConfig, addr: SocketAddr) {
+    if let Some(proxy) = config.reachability_proxy.clone() {
+        tokio::spawn(check(proxy, addr));
+    }
+}
+
+// the hairpin heartbeat only proves the router loops back; coming in through a relay
+// outside the NAT proves players can actually get in
+async fn check(proxy: Socks5Proxy, addr: SocketAddr) {
+    let sources = proxy.addresses().await.unwrap_or_else(|e| {
+        tracing::warn!("Failed to resolve the reachability relay: {}", e);
+        Vec::new()
+    });
+    let _window = heartbeat::ProbeWindow::open(sources);
+    let res = async {
+        let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, proxy.connect(addr)).await??;
+        heartbeat::exchange(&mut stream).await
+    }
+    .await;
+
+    match res {
+        Ok(_) => tracing::info!(
+            "Verified {} is reachable from outside through the relay",
+            addr
+        ),
+        Err(e) => tracing::warn!(
+            "{} is published but not reachable from outside: {}. The NAT probably maps \
+             incoming connections differently (symmetric NAT) or a firewall drops them.",
+            addr,
+            e
+        ),
+    }
+}
@@ -0,0 +1,102 @@
+use std::io;
+
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+#[cfg(target_os = "linux")]
+use crate::splice;
+use crate::{
+    config::ForwardConfig,
+    copy::{Transferred, copy_bidirectional, copy_half},
+    obfuscate::XorStream,
+    tunnel,
+};
+
+// the home side of a tunnel may hand its players to a local unix socket
+#[cfg(unix)]
+pub async fn relay_unix(
+    client_stream: &mut TcpStream,
+    server_stream: &mut UnixStream,
+    config: &ForwardConfig,
+    tunnel: Option<tunnel::Session>,
+    transferred: &Transferred,
+) -> io::Result<()> {
+    if let Some(session) = tunnel {
+        return tunnel::carry(
+            server_stream,
+            client_stream,
+            session,
+            &transferred.down,
+            &transferred.up,
+        )
+        .await;
+    }
+
+    let (mut client_read, mut client_write) = client_stream.split();
+    let (mut server_read, mut server_write) = server_stream.split();
+    let xor = config
+        .obfuscation_key
+        .as_deref()
+        .map(|key| XorStream::new(key.as_bytes()));
+    let buffer_size = config.copy_buffer_size;
+
+    tokio::try_join!(
+        copy_half(
+            &mut client_read,
+            &mut server_write,
+            buffer_size,
+            xor.clone(),
+            &transferred.up
+        ),
+        copy_half(
+            &mut server_read,
+            &mut client_write,
+            buffer_size,
+            xor,
+            &transferred.down
+        )
+    )?;
+    Ok(())
+}
+
+pub async fn relay(
+    client_stream: &mut TcpStream,
+    server_stream: &mut TcpStream,
+    config: &ForwardConfig,
+    tunnel: Option<tunnel::Session>,
+    transferred: &Transferred,
+) -> io::Result<()> {
+    if let Some(session) = tunnel {
+        return tunnel::relay(client_stream, server_stream, session, transferred).await;
+    }
+
+    let obfuscation_key = config.obfuscation_key.as_deref().map(str::as_bytes);
+
+    // splice never sees the bytes, so it cannot obfuscate them
+    #[cfg(target_os = "linux")]
+    if config.splice && obfuscation_key.is_none() {
+        match splice::Pipes::new(config.copy_buffer_size) {
+            Ok(pipes) => {
+                return splice::splice_bidirectional(
+                    client_stream,
+                    server_stream,
+                    pipes,
+                    &transferred.up,
+                    &transferred.down,
+                )
+                .await;
+            }
+            Err(e) => tracing::debug!("splice unavailable, falling back to buffered copy: {}", e),
+        }
+    }
+
+    copy_bidirectional(
+        client_stream,
+        server_stream,
+        config.copy_buffer_size,
+        obfuscation_key,
+        transferred,
+    )
+    .await
+}
@@ -0,0 +1,43 @@
+use tokio::signal::unix::{SignalKind, signal};
+
+use crate::{
+    config::{BHConfig, ConfigSource},
+    ddns, forward,
+};
+
+// the DDNS token is applied in place and the forward section rebinds the listener,
+// other changes still need a restart
+pub async fn run(source: ConfigSource) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    while hangup.recv().await.is_some() {
+        if matches!(source, ConfigSource::Stdin) {
+            tracing::warn!("SIGHUP ignored, a configuration read from stdin cannot be reloaded");
+            continue;
+        }
+        tracing::info!(source = %source, "SIGHUP received, reloading configuration");
+
+        // a URL source builds its own runtime, which must not nest inside this one
+        let reload_source = source.clone();
+        let loaded = tokio::task::spawn_blocking(move || {
+            let config = BHConfig::load(&reload_source)?;
+            config.validate()?;
+            anyhow::Ok(config)
+        })
+        .await?;
+
+        match loaded {
+            Ok(config) => {
+                if let Some(ddns_config) = config.ddns.filter(|_| config.services.ddns) {
+                    ddns::update_token(ddns_config.token).await;
+                }
+                forward::reload(config.forward);
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to reload configuration, keeping the current one")
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use std::{io, time::Duration};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+use crate::config::KeepaliveConfig;
+
+pub fn apply_keepalive(stream: &TcpStream, config: &KeepaliveConfig) -> io::Result<()> {
+    let sock = SockRef::from(stream);
+    if !config.enabled {
+        return sock.set_keepalive(false);
+    }
+
+    let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(config.time));
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        windows
+    ))]
+    let keepalive = keepalive.with_interval(Duration::from_secs(config.interval));
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd"
+    ))]
+    let keepalive = keepalive.with_retries(config.retries);
+
+    sock.set_tcp_keepalive(&keepalive)
+}
@@ -0,0 +1,212 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, bail};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct Socks5Proxy {
+    host: String,
+    port: u16,
+    auth: Option<(String, String)>,
+}
+
+impl TryFrom<String> for Socks5Proxy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let url = Url::parse(&value)?;
+        if url.scheme() != "socks5" {
+            bail!("Unsupported upstream proxy scheme: {}", url.scheme());
+        }
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("Upstream proxy URL has no host"))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = url.port().unwrap_or(1080);
+
+        let auth = if url.username().is_empty() {
+            None
+        } else {
+            Some((
+                percent_decode(url.username())?,
+                percent_decode(url.password().unwrap_or_default())?,
+            ))
+        };
+
+        Ok(Self { host, port, auth })
+    }
+}
+
+impl From<Socks5Proxy> for String {
+    fn from(value: Socks5Proxy) -> Self {
+        let host = match value.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+            _ => value.host,
+        };
+
+        match value.auth {
+            Some((user, pass)) => format!(
+                "socks5://{}:{}@{}:{}",
+                percent_encode(&user),
+                percent_encode(&pass),
+                host,
+                value.port
+            ),
+            None => format!("socks5://{}:{}", host, value.port),
+        }
+    }
+}
+
+fn percent_encode(input: &str) -> String {
+    input
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(input: &str) -> anyhow::Result<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3])?;
+            out.push(u8::from_str_radix(hex, 16)?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Ok(String::from_utf8(out)?)
+}
+
+impl Socks5Proxy {
+    // where connections made through the proxy usually come from
+    pub async fn addresses(&self) -> std::io::Result<Vec<IpAddr>> {
+        let addrs = tokio::net::lookup_host((self.host.as_str(), self.port)).await?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+
+    pub async fn connect(&self, target: SocketAddr) -> anyhow::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+
+        let method = if self.auth.is_some() {
+            METHOD_USER_PASS
+        } else {
+            METHOD_NO_AUTH
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            bail!("Upstream proxy is not a SOCKS5 server");
+        }
+        match reply[1] {
+            METHOD_NO_AUTH => {}
+            METHOD_USER_PASS => self.authenticate(&mut stream).await?,
+            METHOD_NONE_ACCEPTABLE => bail!("Upstream proxy rejected our authentication method"),
+            other => bail!("Upstream proxy selected unsupported method {:#04x}", other),
+        }
+
+        let mut request = vec![VERSION, CMD_CONNECT, 0x00];
+        match target.ip() {
+            IpAddr::V4(ip) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+        request.extend_from_slice(&target.port().to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        if head[1] != 0x00 {
+            bail!(
+                "Upstream proxy CONNECT to {} failed: {}",
+                target,
+                reply_message(head[1])
+            );
+        }
+
+        let addr_len = match head[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => stream.read_u8().await? as usize,
+            other => bail!(
+                "Upstream proxy replied with unknown address type {:#04x}",
+                other
+            ),
+        };
+        let mut bound = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(stream)
+    }
+
+    async fn authenticate(&self, stream: &mut TcpStream) -> anyhow::Result<()> {
+        let Some((user, pass)) = &self.auth else {
+            bail!("Upstream proxy requires credentials");
+        };
+        if user.len() > 255 || pass.len() > 255 {
+            bail!("Upstream proxy credentials are too long");
+        }
+
+        let mut request = vec![0x01, user.len() as u8];
+        request.extend_from_slice(user.as_bytes());
+        request.push(pass.len() as u8);
+        request.extend_from_slice(pass.as_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            bail!("Upstream proxy authentication failed");
+        }
+
+        Ok(())
+    }
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::{io::Interest, net::TcpStream};
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    capacity: usize,
+}
+
+impl Pipe {
+    fn new(size_hint: usize) -> io::Result<Self> {
+        let mut fds = [0 as RawFd; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        // growing the pipe may be refused by pipe-max-size, the default capacity still works
+        let size = size_hint.min(i32::MAX as usize) as libc::c_int;
+        unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, size) };
+        let capacity = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_GETPIPE_SZ) };
+        if capacity <= 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            read,
+            write,
+            capacity: capacity as usize,
+        })
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(n as usize)
+}
+
+async fn splice_half(
+    src: &TcpStream,
+    dst: &TcpStream,
+    pipe: &Pipe,
+    total: &AtomicU64,
+) -> io::Result<()> {
+    loop {
+        // the pipe is always drained before the next read, so EAGAIN here can only come from the socket
+        let n = loop {
+            src.readable().await?;
+            match src.try_io(Interest::READABLE, || {
+                splice(src.as_raw_fd(), pipe.write.as_raw_fd(), pipe.capacity)
+            }) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        if n == 0 {
+            if unsafe { libc::shutdown(dst.as_raw_fd(), libc::SHUT_WR) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::NotConnected {
+                    return Err(e);
+                }
+            }
+            return Ok(());
+        }
+
+        let mut pending = n;
+        while pending > 0 {
+            dst.writable().await?;
+            match dst.try_io(Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), dst.as_raw_fd(), pending)
+            }) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(m) => pending -= m,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        total.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct Pipes {
+    a_to_b: Pipe,
+    b_to_a: Pipe,
+}
+
+impl Pipes {
+    pub fn new(size_hint: usize) -> io::Result<Self> {
+        Ok(Self {
+            a_to_b: Pipe::new(size_hint)?,
+            b_to_a: Pipe::new(size_hint)?,
+        })
+    }
+}
+
+pub async fn splice_bidirectional(
+    a: &TcpStream,
+    b: &TcpStream,
+    pipes: Pipes,
+    a_to_b: &AtomicU64,
+    b_to_a: &AtomicU64,
+) -> io::Result<()> {
+    tokio::try_join!(
+        splice_half(a, b, &pipes.a_to_b, a_to_b),
+        splice_half(b, a, &pipes.b_to_a, b_to_a)
+    )?;
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use std::{fs, net::SocketAddr, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct PersistedState {
+    wan_addr: SocketAddr,
+}
+
+pub fn load(path: &Path) -> Option<SocketAddr> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "Failed to read state file");
+            return None;
+        }
+    };
+
+    match serde_json::from_slice::<PersistedState>(&buf) {
+        Ok(state) => Some(state.wan_addr),
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "Ignoring malformed state file");
+            None
+        }
+    }
+}
+
+pub fn save(path: &Path, wan_addr: SocketAddr) -> anyhow::Result<()> {
+    let buf = serde_json::to_vec(&PersistedState { wan_addr })?;
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, buf)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
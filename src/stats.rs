@@ -0,0 +1,65 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use chrono::Local;
+
+use crate::{LAST_DDNS_UPDATE, WAN_ADDR, metrics::METRICS};
+
+// a summary line for setups that do not scrape /metrics
+pub async fn run(interval: Duration) -> anyhow::Result<()> {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick fires immediately, when there is nothing to report yet
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let wan_addr = match WAN_ADDR.get() {
+            Some(addr) => *addr.read().await,
+            None => continue,
+        };
+        let wan_addr = if wan_addr.ip().is_unspecified() {
+            "unknown".to_string()
+        } else {
+            wan_addr.to_string()
+        };
+        let last_update = match *LAST_DDNS_UPDATE.read().await {
+            Some(ts) => format!("{} ago", elapsed((Local::now() - ts).num_seconds())),
+            None => "never".to_string(),
+        };
+
+        tracing::info!(
+            "Stats: {} active sessions, {} since start, {} up, {} down, WAN address {}, last DDNS update {}",
+            METRICS.active_connections.load(Ordering::Relaxed),
+            METRICS.sessions_total.load(Ordering::Relaxed),
+            bytes(METRICS.bytes_up.load(Ordering::Relaxed)),
+            bytes(METRICS.bytes_down.load(Ordering::Relaxed)),
+            wan_addr,
+            last_update
+        );
+    }
+}
+
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", n)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn elapsed(secs: i64) -> String {
+    let secs = secs.max(0);
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
@@ -0,0 +1,124 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use tokio::net::UdpSocket;
+
+use crate::metrics::{self, Kind, Sample};
+
+// stays below the usual 1500 byte MTU after IP and UDP headers
+const MAX_PACKET: usize = 1432;
+
+pub async fn run(addr: String, interval: Duration) -> anyhow::Result<()> {
+    tracing::info!(
+        "Exporting metrics to StatsD at {} every {}s",
+        addr,
+        interval.as_secs()
+    );
+
+    // counters go out as deltas, timers only when a new measurement arrived
+    let mut previous: HashMap<String, f64> = HashMap::new();
+    let mut socket: Option<UdpSocket> = None;
+    let mut failing = false;
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+        let packets = encode(&metrics::samples().await, &mut previous);
+
+        // the agent is optional infrastructure, so never let it take the worker down
+        match flush(&addr, &mut socket, &packets).await {
+            Ok(()) if failing => {
+                tracing::info!("StatsD agent at {} reachable again", addr);
+                failing = false;
+            }
+            Ok(()) => {}
+            Err(e) if !failing => {
+                tracing::warn!("Failed to send metrics to StatsD at {}: {}", addr, e);
+                failing = true;
+                socket = None;
+            }
+            Err(e) => {
+                tracing::debug!("Failed to send metrics to StatsD at {}: {}", addr, e);
+                socket = None;
+            }
+        }
+    }
+}
+
+async fn flush(
+    addr: &str,
+    socket: &mut Option<UdpSocket>,
+    packets: &[String],
+) -> anyhow::Result<()> {
+    if socket.is_none() {
+        let target = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("{} did not resolve to any address", addr))?;
+        let bind: SocketAddr = if target.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let udp = UdpSocket::bind(bind).await?;
+        udp.connect(target).await?;
+        *socket = Some(udp);
+    }
+
+    let udp = socket.as_ref().unwrap();
+    for packet in packets {
+        udp.send(packet.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn encode(samples: &[Sample], previous: &mut HashMap<String, f64>) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+
+    for s in samples {
+        let key = match &s.label {
+            Some((k, v)) => format!("{}:{}:{}", s.name, k, v),
+            None => s.name.to_string(),
+        };
+        let last = previous.insert(key, s.value);
+
+        let (value, suffix) = match s.kind {
+            Kind::Counter => {
+                // a restart of the counter source shows up as a full value rather than a negative delta
+                let delta = match last {
+                    Some(last) if s.value >= last => s.value - last,
+                    _ => s.value,
+                };
+                if delta == 0.0 {
+                    continue;
+                }
+                (delta, "c")
+            }
+            Kind::Gauge => (s.value, "g"),
+            Kind::Timer => {
+                if s.value == 0.0 || last == Some(s.value) {
+                    continue;
+                }
+                (s.value * 1000.0, "ms")
+            }
+        };
+
+        let line = match &s.label {
+            Some((k, v)) => format!("{}:{}|{}|#{}:{}", s.name, value, suffix, k, v),
+            None => format!("{}:{}|{}", s.name, value, suffix),
+        };
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(&line);
+    }
+
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
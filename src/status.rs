@@ -0,0 +1,174 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
+use chrono::Local;
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    LAST_DDNS_UPDATE, LAST_STUN_SUCCESS, LISTENER_READY, NAT_BEHAVIOR, WAN_ADDR, WAN_ADDR6, metrics,
+};
+
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Clone, Copy)]
+pub struct HealthCheck {
+    pub forward: bool,
+    pub stun: bool,
+    pub window: Duration,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; version=0.0.4",
+            body,
+        }
+    }
+
+    fn json(status: &'static str, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+}
+
+async fn status() -> Response {
+    let wan_addr = *WAN_ADDR.get().unwrap().read().await;
+    let wan_addr = (!wan_addr.ip().is_unspecified()).then(|| wan_addr.to_string());
+    let wan_addr6 = WAN_ADDR6.read().await.map(|ip| ip.to_string());
+    let last_update = LAST_DDNS_UPDATE.read().await.map(|ts| ts.to_rfc3339());
+    let last_mapping = LAST_STUN_SUCCESS.read().await.map(|ts| ts.to_rfc3339());
+    let nat = *NAT_BEHAVIOR.read().await;
+    let nat_type = nat.map(|b| b.nat_type().as_str());
+    let nat_mapping = nat.and_then(|b| b.mapping).map(|b| b.as_str());
+    let nat_filtering = nat.and_then(|b| b.filtering).map(|b| b.as_str());
+
+    Response::json(
+        "200 OK",
+        json!({
+            "wan_addr": wan_addr,
+            "wan_addr6": wan_addr6,
+            "last_update": last_update,
+            "last_mapping": last_mapping,
+            "nat_type": nat_type,
+            "nat_mapping": nat_mapping,
+            "nat_filtering": nat_filtering,
+            "started_at": metrics::start_time().to_rfc3339(),
+            "uptime_seconds": metrics::uptime().as_secs(),
+        }),
+    )
+}
+
+async fn healthz(health: HealthCheck) -> Response {
+    let listener_ready = LISTENER_READY.load(Ordering::Relaxed);
+    let last_mapping = *LAST_STUN_SUCCESS.read().await;
+    let mapping_fresh = last_mapping.is_some_and(|ts| {
+        (Local::now() - ts)
+            .to_std()
+            .is_ok_and(|age| age <= health.window)
+    });
+
+    let healthy = (!health.forward || listener_ready) && (!health.stun || mapping_fresh);
+    let body = json!({
+        "healthy": healthy,
+        "listener_ready": listener_ready,
+        "last_mapping": last_mapping.map(|ts| ts.to_rfc3339()),
+    });
+
+    if healthy {
+        Response::json("200 OK", body)
+    } else {
+        Response::json("503 Service Unavailable", body)
+    }
+}
+
+async fn route(method: &str, path: &str, health: HealthCheck) -> Response {
+    if method != "GET" {
+        return Response::json(
+            "405 Method Not Allowed",
+            json!({ "error": "method not allowed" }),
+        );
+    }
+
+    match path {
+        "/" | "/status" => status().await,
+        "/healthz" => healthz(health).await,
+        "/metrics" => Response::text("200 OK", metrics::render().await),
+        _ => Response::json("404 Not Found", json!({ "error": "not found" })),
+    }
+}
+
+async fn read_request_head(stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            anyhow::bail!("Request head too large");
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed before request completed");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+async fn handle(mut stream: TcpStream, health: HealthCheck) -> anyhow::Result<()> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream)).await??;
+
+    let mut parts = head.lines().next().unwrap_or_default().split(' ');
+    let method = parts.next().unwrap_or_default();
+    let path = parts
+        .next()
+        .unwrap_or_default()
+        .split('?')
+        .next()
+        .unwrap_or_default();
+
+    let resp = route(method, path, health).await;
+    let raw = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        resp.status,
+        resp.content_type,
+        resp.body.len(),
+        resp.body
+    );
+    stream.write_all(raw.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+pub async fn run(bind: IpAddr, port: u16, health: HealthCheck) -> anyhow::Result<()> {
+    let addr = SocketAddr::new(bind, port);
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("Status API listening on http://{}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, health).await {
+                tracing::debug!("Status request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
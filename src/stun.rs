@@ -1,175 +1,443 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+mod client;
+mod error;
+mod flap;
+mod message;
+mod nat;
+mod turn;
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::Ordering,
+};
 
 use anyhow::anyhow;
+use chrono::Local;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpSocket, TcpStream, lookup_host},
+    task::JoinSet,
+    time::Instant,
+};
+use tracing::{Instrument, field::Empty};
+
+use crate::{
+    LAST_STUN_SUCCESS, NAT_BEHAVIOR,
+    config::{GeneralConfig, KeepaliveConfig, TurnConfig},
+    ddns, heartbeat,
+    metrics::METRICS,
+    reachability, sockopt,
 };
+use client::{Client, ClientConfig, LongTermAuth};
+pub use error::StunError;
+use flap::FlapDetector;
+pub use nat::{NatBehavior, NatType};
+
+async fn stun_connect(
+    server: SocketAddr,
+    client_port: u16,
+    keepalive: &KeepaliveConfig,
+) -> Result<TcpStream, StunError> {
+    let (socket, unspecified) = if server.is_ipv6() {
+        (TcpSocket::new_v6()?, IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+    } else {
+        (TcpSocket::new_v4()?, IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    };
 
-use crate::{WAN_ADDR, config::GeneralConfig, ddns::PROVIDER};
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.set_nodelay(true)?;
 
-const STUN_MAGIC_COOKIE: u32 = 0x2112A442;
-const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+    let local_addr = SocketAddr::new(unspecified, client_port);
+    socket.bind(local_addr)?;
 
-fn parse_addr(buf: &[u8]) -> anyhow::Result<SocketAddr> {
-    if buf.len() < 20 {
-        return Err(anyhow!("Mismatched message length."));
+    let stream = tokio::time::timeout(std::time::Duration::from_secs(3), socket.connect(server))
+        .await
+        .map_err(|_| StunError::Timeout("the STUN server to accept the connection"))?
+        .map_err(StunError::Connect)?;
+    if let Err(e) = sockopt::apply_keepalive(&stream, keepalive) {
+        tracing::warn!("Failed to configure TCP keepalive: {}", e);
     }
+    Ok(stream)
+}
 
-    let mut pos = 20;
-    while pos + 4 <= buf.len() {
-        let attr_type = u16::from_be_bytes([buf[pos], buf[pos + 1]]);
-        let attr_len = u16::from_be_bytes([buf[pos + 2], buf[pos + 3]]) as usize;
-        pos += 4;
+async fn lookup_servers(host: &str, port: u16, ipv6: bool) -> Result<Vec<SocketAddr>, StunError> {
+    let addrs: Vec<SocketAddr> = lookup_host(format!("{}:{}", host, port))
+        .await
+        .map_err(|e| StunError::Dns(format!("{}: {}", host, e)))?
+        .filter(|ip| ip.is_ipv6() == ipv6)
+        .collect();
+    if addrs.is_empty() {
+        return Err(StunError::Dns(format!(
+            "No {} address found for {}",
+            if ipv6 { "IPv6" } else { "IPv4" },
+            host
+        )));
+    }
+    Ok(addrs)
+}
 
-        if attr_type == ATTR_XOR_MAPPED_ADDRESS {
-            let x_port = u16::from_be_bytes([buf[pos + 2], buf[pos + 3]]);
-            let x_ip = [buf[pos + 4], buf[pos + 5], buf[pos + 6], buf[pos + 7]];
+async fn lookup_server(host: &str, port: u16, ipv6: bool) -> Result<SocketAddr, StunError> {
+    Ok(lookup_servers(host, port, ipv6).await?[0])
+}
 
-            let port = x_port ^ (STUN_MAGIC_COOKIE >> 16) as u16;
+fn client_config(config: &GeneralConfig) -> ClientConfig {
+    let auth = config
+        .stun_username
+        .clone()
+        .zip(config.stun_password.clone())
+        .map(|(username, password)| LongTermAuth {
+            username,
+            password,
+            realm: config.stun_realm.clone(),
+        });
+    ClientConfig::new(software(config), auth)
+}
 
-            let mc_bytes = STUN_MAGIC_COOKIE.to_be_bytes();
-            let ip = Ipv4Addr::new(
-                x_ip[0] ^ mc_bytes[0],
-                x_ip[1] ^ mc_bytes[1],
-                x_ip[2] ^ mc_bytes[2],
-                x_ip[3] ^ mc_bytes[3],
-            );
-            return Ok(SocketAddr::new(IpAddr::V4(ip), port));
-        }
-        pos += attr_len;
-    }
+fn software(config: &GeneralConfig) -> Option<String> {
+    config.stun_software.then(|| match &config.instance_name {
+        Some(name) => format!("{} ({})", message::SOFTWARE, name),
+        None => message::SOFTWARE.to_string(),
+    })
+}
 
-    Err(anyhow!("XOR-MAPPED-ADDRESS attribute not found。"))
+async fn binding_request(
+    stream: TcpStream,
+    config: &GeneralConfig,
+) -> Result<SocketAddr, StunError> {
+    message::parse_addr(&binding_request_raw(stream, config).await?)
 }
 
-async fn stun_connect(server: SocketAddr, client_port: u16) -> anyhow::Result<TcpStream> {
-    let socket = TcpSocket::new_v4()?;
+async fn binding_request_raw(
+    stream: TcpStream,
+    config: &GeneralConfig,
+) -> Result<Vec<u8>, StunError> {
+    let mut client = Client::new(stream, client_config(config));
+    client.request(message::BINDING_REQUEST, &[]).await
+}
 
-    socket.set_reuseaddr(true)?;
-    #[cfg(unix)]
-    socket.set_reuseport(true)?;
-    socket.set_nodelay(true)?;
-    socket.set_keepalive(true)?;
+// every address of every configured host, so a host with one dead address still races
+async fn resolve_servers(config: &GeneralConfig) -> Vec<SocketAddr> {
+    let hosts = std::iter::once((config.stun_server_host.as_str(), config.stun_server_port)).chain(
+        config
+            .stun_servers
+            .iter()
+            .map(|server| (server.host.as_str(), server.port)),
+    );
+    let mut servers = Vec::new();
+    for (host, port) in hosts {
+        match lookup_servers(host, port, false).await {
+            Ok(addrs) => {
+                for addr in addrs {
+                    if !servers.contains(&addr) {
+                        servers.push(addr);
+                    }
+                }
+            }
+            Err(e) => tracing::warn!("{}", e),
+        }
+    }
+    servers
+}
 
-    let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), client_port);
-    socket.bind(local_addr)?;
+struct Binding {
+    mapped: SocketAddr,
+    response: Vec<u8>,
+    // from sending the request to parsing the answer, the connect is not included
+    rtt: std::time::Duration,
+}
 
-    let stream =
-        tokio::time::timeout(std::time::Duration::from_secs(3), socket.connect(server)).await??;
+async fn query_server(
+    config: &GeneralConfig,
+    server: SocketAddr,
+    local_port: u16,
+) -> Result<Binding, StunError> {
+    let stream = stun_connect(server, local_port, &config.stun_keepalive).await?;
+    let sent_at = Instant::now();
+    let response = binding_request_raw(stream, config).await?;
+    let mapped = message::parse_addr(&response)?;
+    let rtt = sent_at.elapsed();
+    tracing::debug!("STUN server {} answered in {:?}", server, rtt);
+    Ok(Binding {
+        mapped,
+        response,
+        rtt,
+    })
+}
 
-    Ok(stream)
+type Query = (SocketAddr, Result<Binding, StunError>);
+
+fn spawn_queries(
+    config: &GeneralConfig,
+    servers: &[SocketAddr],
+    local_port: u16,
+) -> JoinSet<Query> {
+    let mut queries = JoinSet::new();
+    for &server in servers {
+        let config = config.clone();
+        queries.spawn(async move { (server, query_server(&config, server, local_port).await) });
+    }
+    queries
 }
 
-async fn get_addr(config: GeneralConfig, local_port: u16) -> anyhow::Result<SocketAddr> {
-    let server_addr = loop {
-        match lookup_host(format!(
-            "{}:{}",
-            config.stun_server_host, config.stun_server_port
-        ))
-        .await
-        {
-            Ok(mut addrs) => {
-                if let Some(addr) = addrs.find(|ip| ip.is_ipv4()) {
-                    break addr;
-                }
-            }
-            Err(e) => tracing::warn!("DNS lookup failed: {}, retrying...", e),
+// asks every server at once from the same local port and takes the first mapping, handing
+// back the queries still in flight
+async fn race(
+    config: &GeneralConfig,
+    local_port: u16,
+) -> Result<(SocketAddr, Binding, JoinSet<Query>), StunError> {
+    let servers = loop {
+        let servers = resolve_servers(config).await;
+        if !servers.is_empty() {
+            break servers;
         }
+        tracing::warn!("No STUN server could be resolved, retrying...");
         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
     };
 
     tracing::info!("Register stun worker.");
 
-    let mut stream = loop {
-        match stun_connect(server_addr, local_port).await {
-            Ok(s) => {
-                tracing::info!("Successfully connected to STUN server.");
-                break s;
-            }
-            Err(e) => {
-                tracing::error!("Failed to connect to STUN server: {}, retrying in 5s...", e);
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    loop {
+        let mut queries = spawn_queries(config, &servers, local_port);
+        let mut answered = None;
+        while let Some(res) = queries.join_next().await {
+            let Ok((server, res)) = res else { continue };
+            match res {
+                Ok(binding) => {
+                    tracing::info!("Successfully queried STUN server {}.", server);
+                    return Ok((server, binding, queries));
+                }
+                Err(e) => {
+                    METRICS.stun_errors[e.kind_index()].fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!("STUN server {} failed: {}", server, e);
+                    // a server that answered wrongly is worth reporting, silence is not
+                    if !matches!(e, StunError::Connect(_) | StunError::Timeout(_)) {
+                        answered.get_or_insert(e);
+                    }
+                }
             }
         }
-    };
-
-    let mut request = [0u8; 20];
-    request[0..2].copy_from_slice(&0x0001u16.to_be_bytes());
-    request[4..8].copy_from_slice(&0x2112A442u32.to_be_bytes());
-    request[8..20].copy_from_slice(&[0xAA; 12]);
 
-    stream.write_all(&request).await?;
-
-    let mut response = [0u8; 1024];
-    let _ = stream.read(&mut response).await?;
+        if let Some(e) = answered {
+            return Err(e);
+        }
+        tracing::error!("No STUN server could be reached, retrying in 5s...");
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    }
+}
 
-    let addr = parse_addr(&response)?;
-    let host = addr.ip();
-    let port = addr.port();
+// the slower servers still answer; a different mapping means the NAT maps per destination
+// or one of the servers is misbehaving
+async fn cross_check(mut queries: JoinSet<Query>, winner: SocketAddr, mapped: SocketAddr) {
+    while let Some(res) = queries.join_next().await {
+        if let Ok((server, Ok(Binding { mapped: other, .. }))) = res
+            && other != mapped
+        {
+            tracing::warn!(
+                "STUN servers disagree on the mapped address: {} reported {}, {} reported {}. \
+                 The NAT may map each destination differently or one server is misbehaving.",
+                winner,
+                mapped,
+                server,
+                other
+            );
+        }
+    }
+}
 
-    tracing::info!("Public addr: {}", addr);
+// the first answer among the remaining queries, as (server, mapped address)
+async fn second_opinion(queries: &mut JoinSet<Query>) -> Option<(SocketAddr, SocketAddr)> {
+    while let Some(res) = queries.join_next().await {
+        match res {
+            Ok((server, Ok(binding))) => return Some((server, binding.mapped)),
+            Ok((server, Err(e))) => {
+                tracing::debug!(
+                    "STUN server {} could not confirm the mapping: {}",
+                    server,
+                    e
+                )
+            }
+            Err(_) => {}
+        }
+    }
+    None
+}
 
-    loop {
-        match PROVIDER
-            .get()
-            .unwrap()
-            .update_srv(&host.to_string(), port)
-            .await
-        {
-            Ok(()) => break,
+// the winner of the last race is asked alone, a failure puts every server back in the race;
+// with stun_confirm a mapping other than `current` also needs a second server to agree
+async fn get_addr(
+    config: &GeneralConfig,
+    local_port: u16,
+    fastest: &mut Option<SocketAddr>,
+    current: Option<SocketAddr>,
+) -> Result<SocketAddr, StunError> {
+    let preferred = match *fastest {
+        Some(server) => match query_server(config, server, local_port).await {
+            Ok(binding) => Some((server, binding)),
             Err(e) => {
-                tracing::error!(
-                    "An error occurred while updating the SRV record: {}, retrying in 5s...",
+                METRICS.stun_errors[e.kind_index()].fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "STUN server {} failed: {}, racing all servers again",
+                    server,
                     e
                 );
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                None
             }
+        },
+        None => None,
+    };
+    let (server_addr, binding, others) = match preferred {
+        Some((server, binding)) => (server, binding, None),
+        None => {
+            let (server, binding, others) = race(config, local_port).await?;
+            (server, binding, Some(others))
+        }
+    };
+    *fastest = Some(server_addr);
+    let addr = binding.mapped;
+
+    let mut others = match others {
+        Some(others) => others,
+        // nobody else was asked this time, but a new mapping needs a witness
+        None if config.stun_confirm && current != Some(addr) => {
+            let servers: Vec<SocketAddr> = resolve_servers(config)
+                .await
+                .into_iter()
+                .filter(|server| *server != server_addr)
+                .collect();
+            spawn_queries(config, &servers, local_port)
         }
+        None => JoinSet::new(),
+    };
+    if config.stun_confirm && current != Some(addr) {
+        match second_opinion(&mut others).await {
+            Some((server, other)) if other == addr => {
+                tracing::info!("New mapping {} confirmed by {}", addr, server)
+            }
+            other => {
+                tokio::spawn(cross_check(others, server_addr, addr));
+                return Err(StunError::Unconfirmed {
+                    mapped: addr,
+                    other,
+                });
+            }
+        }
+    }
+    tokio::spawn(cross_check(others, server_addr, addr));
+
+    tracing::info!("Public addr: {}", addr);
+    METRICS
+        .stun_rtt_us
+        .store(binding.rtt.as_micros() as u64, Ordering::Relaxed);
+    *LAST_STUN_SUCCESS.write().await = Some(Local::now());
+
+    // the NAT does not change under a running instance, one diagnosis is enough
+    if NAT_BEHAVIOR.read().await.is_none() {
+        let behavior = nat::detect(config, local_port, server_addr, &binding.response).await;
+        log_nat_behavior(&behavior, addr);
+        *NAT_BEHAVIOR.write().await = Some(behavior);
+    }
+
+    Ok(addr)
+}
+
+fn log_nat_behavior(behavior: &NatBehavior, mapped: SocketAddr) {
+    let describe = |b: Option<nat::Behavior>| b.map_or("unknown", nat::Behavior::as_str);
+    tracing::info!(
+        "NAT type: {} (mapping {}, filtering {})",
+        behavior.nat_type().as_str(),
+        describe(behavior.mapping),
+        describe(behavior.filtering)
+    );
+    if behavior.nat_type() == NatType::Symmetric {
+        tracing::warn!(
+            "Symmetric NAT detected: the mapping of {} changes with the destination. \
+             Inbound connections to the published address will most likely fail.",
+            mapped
+        );
     }
+}
 
+// IPv6 is rarely NATed, so a single attempt without NAT detection is enough; the
+// caller falls back to IPv4 only when it fails
+async fn get_addr6(config: &GeneralConfig, local_port: u16) -> Result<SocketAddr, StunError> {
+    let server = lookup_server(&config.stun_server_host, config.stun_server_port, true).await?;
+    let stream = stun_connect(server, local_port, &config.stun_keepalive).await?;
+    let addr = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        binding_request(stream, config),
+    )
+    .await
+    .map_err(|_| StunError::Timeout("the IPv6 binding response"))??;
+
+    tracing::info!("Public IPv6 addr: {}", addr);
     Ok(addr)
 }
 
-async fn heartbeat_loop(addr: SocketAddr, heartbeat: u64) -> anyhow::Result<()> {
-    async fn conn(addr: SocketAddr) -> anyhow::Result<TcpStream> {
+async fn query_v6(config: &GeneralConfig, local_port: u16) -> Option<IpAddr> {
+    if !config.ipv6 {
+        return None;
+    }
+    match get_addr6(config, local_port).await {
+        Ok(addr) => Some(addr.ip()),
+        Err(e) => {
+            tracing::warn!("IPv6 address check failed: {}", e);
+            None
+        }
+    }
+}
+
+async fn needs_relay(turn: &Option<TurnConfig>) -> bool {
+    turn.is_some()
+        && NAT_BEHAVIOR
+            .read()
+            .await
+            .is_some_and(|b| b.nat_type() == NatType::Symmetric)
+}
+
+async fn heartbeat_loop(
+    addr: SocketAddr,
+    heartbeat: u64,
+    keepalive: &KeepaliveConfig,
+) -> anyhow::Result<()> {
+    async fn conn(addr: SocketAddr, keepalive: &KeepaliveConfig) -> anyhow::Result<TcpStream> {
         let socket = TcpSocket::new_v4()?;
-        socket.set_keepalive(true)?;
 
         let stream =
             tokio::time::timeout(std::time::Duration::from_secs(5), socket.connect(addr)).await??;
+        if let Err(e) = sockopt::apply_keepalive(&stream, keepalive) {
+            tracing::warn!("Failed to configure TCP keepalive: {}", e);
+        }
 
         Ok(stream)
     }
-    let mut stream = conn(addr)
-        .await
-        .map_err(|e| anyhow!("Initial connect failed: {}", e))?;
+    let mut stream = conn(addr, keepalive).await.map_err(|e| {
+        METRICS.heartbeat_failures.fetch_add(1, Ordering::Relaxed);
+        anyhow!("Initial connect failed: {}", e)
+    })?;
     tracing::info!("Successfully connected to heartbeat server.");
 
     let timeout = std::time::Duration::from_secs(heartbeat);
-    let io_timeout = std::time::Duration::from_secs(5);
-    let data = b"hbpk";
-    let expected_resp = b"hbre";
 
     loop {
         let res: anyhow::Result<()> = async {
-            tokio::time::timeout(io_timeout, stream.write_all(data)).await??;
-
-            let mut buf = [0u8; 64];
-            let n = tokio::time::timeout(io_timeout, stream.read(&mut buf)).await??;
-
-            if n == 0 {
-                return Err(anyhow::anyhow!("Connection closed by remote peer"));
+            let sent_at = Instant::now();
+            let resp = heartbeat::exchange(&mut stream).await?;
+
+            let rtt = sent_at.elapsed();
+            METRICS.heartbeat_success.fetch_add(1, Ordering::Relaxed);
+            METRICS
+                .heartbeat_rtt_us
+                .store(rtt.as_micros() as u64, Ordering::Relaxed);
+            match heartbeat::decode_stats(&resp) {
+                Some(stats) => tracing::debug!(
+                    "Heartbeat packet sent, round trip {:?}, {} active connections, up {}s.",
+                    rtt,
+                    stats.active_connections,
+                    stats.uptime.as_secs()
+                ),
+                None => tracing::debug!("Heartbeat packet sent, round trip {:?}.", rtt),
             }
-
-            if n < expected_resp.len() || &buf[..expected_resp.len()] != expected_resp {
-                return Err(anyhow::anyhow!(
-                    "Invalid heartbeat response: {:?}",
-                    &buf[..n]
-                ));
-            }
-
-            tracing::info!("Heartbeat packet sent.");
+            *LAST_STUN_SUCCESS.write().await = Some(Local::now());
             tokio::time::sleep(timeout).await;
 
             Ok::<(), anyhow::Error>(())
@@ -177,7 +445,11 @@ async fn heartbeat_loop(addr: SocketAddr, heartbeat: u64) -> anyhow::Result<()>
         .await;
 
         if let Err(e) = res {
-            tracing::error!("Heartbeat error: {}. Retrying in 5s...", e);
+            METRICS.heartbeat_failures.fetch_add(1, Ordering::Relaxed);
+            tracing::error!(
+                "Heartbeat error: {}, the public address may not be reachable from outside. Retrying in 5s...",
+                e
+            );
             tokio::time::sleep(std::time::Duration::from_secs(5)).await;
 
             return Err(e);
@@ -185,57 +457,408 @@ async fn heartbeat_loop(addr: SocketAddr, heartbeat: u64) -> anyhow::Result<()>
     }
 }
 
-pub async fn run(config: GeneralConfig, local_port: u16) {
-    let config_cloned = config.clone();
-    let heartbeat = config.heartbeat;
-    let mut wan_addr = get_addr(config, local_port).await.unwrap_or_else(|e| {
-        tracing::error!("{:?}", e);
-
-        std::process::exit(1);
-    });
-
-    {
-        let mut wa = WAN_ADDR.get().unwrap().write().await;
-        *wa = wan_addr;
-    }
-
-    tokio::spawn(async move {
-        let mut retries = 0;
-        loop {
-            if retries >= 3 {
-                match get_addr(config_cloned.clone(), local_port).await {
-                    Ok(new_addr) => {
-                        wan_addr = new_addr;
-                        retries = 0;
+// one pass of run's loop; each state starts from scratch rather than reusing a half-done one
+enum Step {
+    // fresh STUN queries for the current mapping
+    Query,
+    // push addr to DNS, advancing the published address only once it is confirmed
+    Publish(SocketAddr),
+    // heartbeat the mapping until the next check is due or it breaks
+    Watch,
+    Sleep(std::time::Duration),
+}
 
-                        {
-                            let mut wa = WAN_ADDR.get().unwrap().write().await;
-                            *wa = wan_addr;
+pub async fn run(
+    config: GeneralConfig,
+    turn: Option<TurnConfig>,
+    local_port: u16,
+    heartbeat_enabled: bool,
+) -> anyhow::Result<()> {
+    let keepalive = config.binding_keepalive_interval;
+    let address_check = std::time::Duration::from_secs(config.address_check_interval);
+    let mut published = ddns::last_published(&config).await;
+
+    let mut flaps = FlapDetector::new(
+        config.flap_threshold,
+        std::time::Duration::from_secs(config.flap_window),
+    );
+    let mut wan_addr = None;
+    let mut fastest = None;
+    let mut next_check = Instant::now();
+    let mut retries = 0;
+    let mut step = Step::Query;
+    loop {
+        step = match step {
+            Step::Query => {
+                let span = tracing::info_span!(
+                    "stun_query",
+                    server = %config.stun_server_host,
+                    mapped = Empty,
+                    result = Empty,
+                );
+                let (res, v6) = async {
+                    tokio::join!(
+                        get_addr(&config, local_port, &mut fastest, published),
+                        query_v6(&config, local_port)
+                    )
+                }
+                .instrument(span.clone())
+                .await;
+                match &res {
+                    Ok(addr) => span
+                        .record("mapped", tracing::field::display(addr))
+                        .record("result", "ok"),
+                    Err(e) => span.record("result", tracing::field::display(e)),
+                };
+                if config.ipv6 {
+                    ddns::publish_v6(v6).await;
+                }
+                match res {
+                    Ok(addr) => {
+                        if needs_relay(&turn).await {
+                            return turn::run(
+                                turn.as_ref().unwrap(),
+                                &config,
+                                local_port,
+                                published,
+                            )
+                            .await;
                         }
-
-                        tracing::info!("Global WAN address synchronized: {}", new_addr);
+                        Step::Publish(addr)
                     }
+                    // nothing to heartbeat yet, let the supervisor restart the worker
+                    Err(e) if wan_addr.is_none() => return Err(e.into()),
                     Err(e) => {
+                        let delay = if e.is_transient() { 10 } else { 60 };
                         tracing::error!(
-                            "Failed to re-fetch WAN address: {}, retrying in 10s...",
-                            e
+                            "Failed to re-fetch WAN address: {}, retrying in {}s...",
+                            e,
+                            delay
                         );
-                        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-                        continue;
+                        Step::Sleep(std::time::Duration::from_secs(delay))
                     }
                 }
             }
-            if let Err(e) = heartbeat_loop(wan_addr, heartbeat).await {
-                tracing::error!(
-                    "Heartbeat session ended: {}. Retry count: {}",
-                    e,
-                    retries + 1
-                );
-                retries += 1;
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-            } else {
+            Step::Publish(addr) => {
+                if let Some(previous) = wan_addr
+                    && previous != addr
+                {
+                    METRICS.wan_address_changes.fetch_add(1, Ordering::Relaxed);
+                    flaps.record_change(previous, addr);
+                }
+                wan_addr = Some(addr);
                 retries = 0;
+                next_check = Instant::now() + address_check;
+                if ddns::publish(&config.state_file, addr, published).await {
+                    if published != Some(addr) {
+                        reachability::spawn_check(&config, addr);
+                    }
+                    published = Some(addr);
+                    tracing::info!("Global WAN address synchronized: {}", addr);
+                }
+                Step::Watch
+            }
+            Step::Watch => match wan_addr {
+                Some(_) if retries >= 3 || Instant::now() >= next_check => Step::Query,
+                // without a forward listener nobody answers heartbeats, so only re-query on a timer
+                Some(_) if !heartbeat_enabled => {
+                    tokio::time::sleep_until(next_check).await;
+                    Step::Query
+                }
+                Some(addr) => tokio::select! {
+                    res = heartbeat_loop(addr, keepalive, &config.stun_keepalive) => match res {
+                        Err(e) => {
+                            tracing::error!(
+                                "Heartbeat session ended: {}. Retry count: {}",
+                                e,
+                                retries + 1
+                            );
+                            retries += 1;
+                            Step::Sleep(std::time::Duration::from_secs(5))
+                        }
+                        Ok(()) => Step::Watch,
+                    },
+                    _ = tokio::time::sleep_until(next_check) => Step::Query,
+                },
+                None => Step::Query,
+            },
+            Step::Sleep(delay) => {
+                tokio::time::sleep(delay).await;
+                Step::Watch
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque, net::SocketAddrV4, path::PathBuf, sync::Mutex, time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::RwLock,
+    };
+
+    use super::*;
+    use crate::{
+        WAN_ADDR,
+        ddns::{DdnsError, DynamicDns},
+        state,
+    };
+
+    // PROVIDER and the other worker globals are shared, so tests driving run take turns
+    static SERIAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    // update_srv calls as (host, port), and the errors the next calls fail with
+    static UPDATES: Mutex<Vec<(String, u16)>> = Mutex::new(Vec::new());
+    static UPDATE_ERRORS: Mutex<VecDeque<DdnsError>> = Mutex::new(VecDeque::new());
+    static VERIFY_ERRORS: Mutex<VecDeque<DdnsError>> = Mutex::new(VecDeque::new());
+
+    struct StubDns;
+
+    #[async_trait]
+    impl DynamicDns for StubDns {
+        async fn update_srv(&self, host: &str, port: u16) -> Result<(), DdnsError> {
+            UPDATES.lock().unwrap().push((host.to_string(), port));
+            UPDATE_ERRORS
+                .lock()
+                .unwrap()
+                .pop_front()
+                .map_or(Ok(()), Err)
+        }
+
+        async fn update_aaaa(&self, _ip: Option<Ipv6Addr>) -> Result<(), DdnsError> {
+            Ok(())
+        }
+
+        async fn update_a_records(&self, _ips: &[IpAddr]) -> Result<(), DdnsError> {
+            Ok(())
+        }
+
+        async fn verify(&self, host: &str, port: u16) -> Result<bool, DdnsError> {
+            if let Some(e) = VERIFY_ERRORS.lock().unwrap().pop_front() {
+                return Err(e);
+            }
+            let last = UPDATES.lock().unwrap().last().cloned();
+            Ok(last == Some((host.to_string(), port)))
+        }
+
+        async fn delete_record(&self, _rectype: &str, _name: &str) -> Result<(), DdnsError> {
+            Ok(())
+        }
+
+        async fn update_token(&self, _token: String) -> Result<bool, DdnsError> {
+            Ok(false)
+        }
+
+        fn records(&self) -> Vec<(&'static str, String)> {
+            Vec::new()
+        }
+    }
+
+    fn updates() -> Vec<(String, u16)> {
+        UPDATES.lock().unwrap().clone()
+    }
+
+    async fn setup() -> tokio::sync::MutexGuard<'static, ()> {
+        let guard = SERIAL.lock().await;
+        let _ = ddns::PROVIDER.set(Box::new(StubDns));
+        let _ = WAN_ADDR.set(RwLock::new(SocketAddr::from(([0, 0, 0, 0], 0))));
+        // NAT detection would probe servers the tests do not run
+        *NAT_BEHAVIOR.write().await = Some(NatBehavior {
+            mapping: None,
+            filtering: None,
+        });
+        UPDATES.lock().unwrap().clear();
+        UPDATE_ERRORS.lock().unwrap().clear();
+        VERIFY_ERRORS.lock().unwrap().clear();
+        guard
+    }
+
+    #[derive(Clone, Copy)]
+    enum Answer {
+        Mapped(SocketAddrV4),
+        // the first bytes of a response, then the connection is closed
+        Truncated,
+    }
+
+    // answers every binding request on its own connection, like a TCP STUN server
+    async fn stun_server(answer: Answer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let len = loop {
+                        if let Some(len) = message::framed_len(&buf).unwrap() {
+                            break len;
+                        }
+                        if stream.read_buf(&mut buf).await.unwrap() == 0 {
+                            return;
+                        }
+                    };
+                    let transaction_id = message::transaction_id_of(&buf[..len]);
+                    let mapped = match answer {
+                        Answer::Mapped(mapped) => mapped,
+                        Answer::Truncated => SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1),
+                    };
+                    let response = message::MessageBuilder::new(
+                        message::BINDING_REQUEST | 0x0100,
+                        transaction_id.try_into().unwrap(),
+                    )
+                    .attr(
+                        message::ATTR_XOR_MAPPED_ADDRESS,
+                        &message::encode_xor_addr(mapped),
+                    )
+                    .finish();
+                    let response = match answer {
+                        Answer::Mapped(_) => &response[..],
+                        Answer::Truncated => &response[..12],
+                    };
+                    let _ = stream.write_all(response).await;
+                });
             }
+        });
+        addr
+    }
+
+    fn state_file(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("bedrockhole-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn config(server: SocketAddr, state_file: PathBuf) -> GeneralConfig {
+        GeneralConfig {
+            stun_server_host: server.ip().to_string(),
+            stun_server_port: server.port(),
+            address_check_interval: 1,
+            state_file,
+            ..GeneralConfig::default()
         }
-    });
+    }
+
+    async fn wait_for_updates(count: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while updates().len() < count {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("expected {} updates, got {:?}", count, updates()));
+    }
+
+    const MAPPED: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 41234);
+
+    async fn republishes_after(name: &str, fail: impl FnOnce()) {
+        let _serial = setup().await;
+        fail();
+        let server = stun_server(Answer::Mapped(MAPPED)).await;
+        let state_file = state_file(name);
+        let worker = tokio::spawn(run(config(server, state_file.clone()), None, 0, false));
+
+        wait_for_updates(1).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            state::load(&state_file),
+            None,
+            "a failed update was persisted"
+        );
+
+        // still unpublished, so the next check writes the same address again
+        wait_for_updates(2).await;
+        worker.abort();
+        let expected = (MAPPED.ip().to_string(), MAPPED.port());
+        assert_eq!(updates(), [expected.clone(), expected]);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(state::load(&state_file), Some(SocketAddr::V4(MAPPED)));
+        let _ = std::fs::remove_file(&state_file);
+    }
+
+    #[tokio::test]
+    async fn get_addr_reads_the_mapping_from_the_server() {
+        let _serial = setup().await;
+        let server = stun_server(Answer::Mapped(MAPPED)).await;
+        let config = config(server, state_file("get-addr"));
+
+        let mut fastest = None;
+        let mapped = get_addr(&config, 0, &mut fastest, None).await.unwrap();
+        assert_eq!(mapped, SocketAddr::V4(MAPPED));
+        assert_eq!(fastest, Some(server));
+    }
+
+    #[tokio::test]
+    async fn worker_publishes_the_mapped_address() {
+        let _serial = setup().await;
+        let server = stun_server(Answer::Mapped(MAPPED)).await;
+        let state_file = state_file("publishes");
+        let worker = tokio::spawn(run(config(server, state_file.clone()), None, 0, false));
+
+        wait_for_updates(1).await;
+        worker.abort();
+        assert_eq!(updates(), [(MAPPED.ip().to_string(), MAPPED.port())]);
+        assert_eq!(
+            *WAN_ADDR.get().unwrap().read().await,
+            SocketAddr::V4(MAPPED)
+        );
+        let _ = std::fs::remove_file(&state_file);
+    }
+
+    #[tokio::test]
+    async fn failed_update_is_not_remembered_as_published() {
+        republishes_after("update-fails", || {
+            let e = DdnsError::Auth("token rejected".into());
+            UPDATE_ERRORS.lock().unwrap().push_back(e);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn failed_verify_is_not_remembered_as_published() {
+        republishes_after("verify-fails", || {
+            let e = DdnsError::Other("records could not be read back".into());
+            VERIFY_ERRORS.lock().unwrap().push_back(e);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn published_address_is_not_written_again() {
+        let _serial = setup().await;
+        let server = stun_server(Answer::Mapped(MAPPED)).await;
+        let state_file = state_file("unchanged");
+        let worker = tokio::spawn(run(config(server, state_file.clone()), None, 0, false));
+
+        wait_for_updates(1).await;
+        // two more address checks find the same mapping
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        worker.abort();
+        assert_eq!(updates().len(), 1);
+        let _ = std::fs::remove_file(&state_file);
+    }
+
+    #[tokio::test]
+    async fn response_cut_off_mid_query_publishes_nothing() {
+        let _serial = setup().await;
+        let server = stun_server(Answer::Truncated).await;
+        let state_file = state_file("truncated");
+
+        let res = tokio::time::timeout(
+            Duration::from_secs(5),
+            run(config(server, state_file.clone()), None, 0, false),
+        )
+        .await
+        .expect("the worker kept waiting for the rest of the response");
+
+        assert!(res.is_err());
+        assert!(updates().is_empty());
+        assert_eq!(state::load(&state_file), None);
+    }
 }
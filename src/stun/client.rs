@@ -0,0 +1,161 @@
+use std::io;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::{
+    StunError,
+    message::{self, Credentials, MessageBuilder},
+};
+
+#[derive(Clone)]
+pub struct LongTermAuth {
+    pub username: String,
+    pub password: String,
+    pub realm: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ClientConfig {
+    pub software: Option<String>,
+    pub auth: Option<LongTermAuth>,
+    creds: Option<Credentials>,
+}
+
+impl ClientConfig {
+    pub fn new(software: Option<String>, auth: Option<LongTermAuth>) -> Self {
+        Self {
+            software,
+            auth,
+            creds: None,
+        }
+    }
+}
+
+pub struct Client {
+    stream: TcpStream,
+    config: ClientConfig,
+    buf: Vec<u8>,
+    indications: Vec<Vec<u8>>,
+}
+
+impl Client {
+    pub fn new(stream: TcpStream, config: ClientConfig) -> Self {
+        Self {
+            stream,
+            config,
+            buf: Vec::new(),
+            indications: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> ClientConfig {
+        self.config.clone()
+    }
+
+    // cancel safe: partial reads stay in `buf` until a full message has arrived
+    async fn read_message(&mut self) -> Result<Vec<u8>, StunError> {
+        loop {
+            if let Some(len) = message::framed_len(&self.buf)? {
+                let msg: Vec<u8> = self.buf.drain(..len).collect();
+                message::validate(&msg)?;
+                return Ok(msg);
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(StunError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "STUN server closed the connection",
+                )));
+            }
+        }
+    }
+
+    pub async fn next_indication(&mut self) -> Result<Vec<u8>, StunError> {
+        if !self.indications.is_empty() {
+            return Ok(self.indications.remove(0));
+        }
+        loop {
+            let msg = self.read_message().await?;
+            if message::is_indication(&msg) {
+                return Ok(msg);
+            }
+        }
+    }
+
+    fn challenge(&mut self, code: u16, response: &[u8]) -> Result<(), StunError> {
+        let Some(auth) = &self.config.auth else {
+            return Err(StunError::ErrorResponse {
+                code,
+                reason: "authentication required but no credentials are configured".into(),
+            });
+        };
+
+        let nonce = message::find_attr(response, message::ATTR_NONCE)
+            .ok_or_else(|| StunError::Parse("STUN challenge is missing a NONCE".into()))?;
+        let realm = match &auth.realm {
+            Some(realm) => realm.clone(),
+            None => message::find_attr(response, message::ATTR_REALM)
+                .map(|r| String::from_utf8_lossy(r).into_owned())
+                .ok_or_else(|| StunError::Parse("STUN challenge is missing a REALM".into()))?,
+        };
+
+        self.config.creds = Some(Credentials::long_term(
+            &auth.username,
+            &auth.password,
+            &realm,
+            nonce,
+        ));
+        Ok(())
+    }
+
+    pub async fn request(
+        &mut self,
+        msg_type: u16,
+        attrs: &[(u16, &[u8])],
+    ) -> Result<Vec<u8>, StunError> {
+        // 401 hands out the realm and nonce, 438 a fresh nonce once the old one went stale
+        for attempt in 0..3 {
+            let transaction_id = message::transaction_id();
+            let mut builder = MessageBuilder::new(msg_type, transaction_id);
+            for (attr_type, value) in attrs {
+                builder = builder.attr(*attr_type, value);
+            }
+            if let Some(software) = &self.config.software {
+                builder = builder.attr(message::ATTR_SOFTWARE, software.as_bytes());
+            }
+            if let Some(creds) = &self.config.creds {
+                builder = builder.integrity(creds);
+            }
+            self.stream.write_all(&builder.finish()).await?;
+
+            let response = loop {
+                let msg = self.read_message().await?;
+                if message::transaction_id_of(&msg) == transaction_id {
+                    break msg;
+                }
+                if message::is_indication(&msg) {
+                    self.indications.push(msg);
+                }
+            };
+
+            if !message::is_error(&response) {
+                return Ok(response);
+            }
+
+            let (code, reason) = message::error_code(&response).unwrap_or((0, String::new()));
+            if matches!(code, 401 | 438) && attempt < 2 {
+                self.challenge(code, &response)?;
+                continue;
+            }
+            return Err(StunError::ErrorResponse { code, reason });
+        }
+
+        unreachable!("the last attempt always returns")
+    }
+
+    pub fn into_parts(self) -> (TcpStream, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+}
@@ -0,0 +1,107 @@
+use std::{fmt, io, net::SocketAddr};
+
+#[derive(Debug)]
+pub enum StunError {
+    Dns(String),
+    Connect(io::Error),
+    Timeout(&'static str),
+    Io(io::Error),
+    Parse(String),
+    NotStun,
+    ErrorResponse {
+        code: u16,
+        reason: String,
+    },
+    // the second server's (server, mapped address), None when no other server answered
+    Unconfirmed {
+        mapped: SocketAddr,
+        other: Option<(SocketAddr, SocketAddr)>,
+    },
+}
+
+impl StunError {
+    pub const KINDS: [&'static str; 8] = [
+        "dns",
+        "connect",
+        "timeout",
+        "io",
+        "parse",
+        "not_stun",
+        "error_response",
+        "unconfirmed",
+    ];
+
+    // index into KINDS, used to label the error counters
+    pub fn kind_index(&self) -> usize {
+        match self {
+            StunError::Dns(_) => 0,
+            StunError::Connect(_) => 1,
+            StunError::Timeout(_) => 2,
+            StunError::Io(_) => 3,
+            StunError::Parse(_) => 4,
+            StunError::NotStun => 5,
+            StunError::ErrorResponse { .. } => 6,
+            StunError::Unconfirmed { .. } => 7,
+        }
+    }
+
+    // a broken or misconfigured server will not recover within a few seconds
+    pub fn is_transient(&self) -> bool {
+        !matches!(
+            self,
+            StunError::Parse(_)
+                | StunError::NotStun
+                | StunError::ErrorResponse { .. }
+                | StunError::Unconfirmed { .. }
+        )
+    }
+}
+
+impl fmt::Display for StunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StunError::Dns(e) => write!(f, "DNS lookup failed: {}", e),
+            StunError::Connect(e) => write!(f, "Connect failed: {}", e),
+            StunError::Timeout(what) => write!(f, "Timed out waiting for {}", what),
+            StunError::Io(e) => write!(f, "I/O error: {}", e),
+            StunError::Parse(e) => write!(f, "Malformed STUN message: {}", e),
+            StunError::NotStun => {
+                write!(f, "Response lacks the STUN magic cookie, not a STUN server")
+            }
+            StunError::ErrorResponse { code, reason } => {
+                write!(f, "STUN server rejected request: {} {}", code, reason)
+            }
+            StunError::Unconfirmed {
+                mapped,
+                other: Some((server, addr)),
+            } => write!(
+                f,
+                "{} reported {} instead of {}, keeping the current address",
+                server, addr, mapped
+            ),
+            StunError::Unconfirmed {
+                mapped,
+                other: None,
+            } => write!(
+                f,
+                "No second STUN server answered to confirm {}, keeping the current address",
+                mapped
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StunError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StunError::Connect(e) | StunError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StunError {
+    fn from(e: io::Error) -> Self {
+        StunError::Io(e)
+    }
+}
@@ -0,0 +1,52 @@
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
+
+use tokio::time::Instant;
+
+// counts WAN address changes in a sliding window and warns once per burst
+pub struct FlapDetector {
+    threshold: usize,
+    window: Duration,
+    changes: VecDeque<Instant>,
+    alerted: bool,
+}
+
+impl FlapDetector {
+    pub fn new(threshold: u32, window: Duration) -> Self {
+        Self {
+            threshold: threshold as usize,
+            window,
+            changes: VecDeque::new(),
+            alerted: false,
+        }
+    }
+
+    pub fn record_change(&mut self, from: SocketAddr, to: SocketAddr) {
+        let now = Instant::now();
+        while self
+            .changes
+            .front()
+            .is_some_and(|&at| now.duration_since(at) > self.window)
+        {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(now);
+
+        if self.changes.len() < self.threshold {
+            // quiet long enough that another burst deserves its own warning
+            self.alerted = false;
+            return;
+        }
+        if !self.alerted {
+            self.alerted = true;
+            tracing::warn!(
+                "WAN address is unstable: it changed {} times within {}s, latest {} -> {}. \
+                 The ISP, the router or a carrier-grade NAT keeps remapping the connection, \
+                 players will lose the server every time it moves.",
+                self.changes.len(),
+                self.window.as_secs(),
+                from,
+                to
+            );
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+use super::{
+    StunError, binding_request_raw, lookup_server,
+    message::{self, MessageBuilder},
+    stun_connect,
+};
+use crate::config::GeneralConfig;
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+// RFC 5389 retransmits UDP requests; three tries are plenty for a one-off diagnosis
+const UDP_ATTEMPTS: u32 = 3;
+const UDP_WAIT: Duration = Duration::from_secs(1);
+
+const CHANGE_IP: u8 = 0x04;
+const CHANGE_PORT: u8 = 0x02;
+
+// RFC 5780 names for how the NAT treats different destinations
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Behavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+impl Behavior {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Behavior::EndpointIndependent => "endpoint-independent",
+            Behavior::AddressDependent => "address-dependent",
+            Behavior::AddressAndPortDependent => "address-and-port-dependent",
+        }
+    }
+}
+
+// the classic RFC 3489 names, derived from both behaviors
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    Unknown,
+    FullCone,
+    Restricted,
+    PortRestricted,
+    Symmetric,
+}
+
+impl NatType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NatType::Unknown => "unknown",
+            NatType::FullCone => "full-cone",
+            NatType::Restricted => "restricted",
+            NatType::PortRestricted => "port-restricted",
+            NatType::Symmetric => "symmetric",
+        }
+    }
+}
+
+// None where the test could not be run or the server lacks RFC 5780 support
+#[derive(Clone, Copy, Default)]
+pub struct NatBehavior {
+    pub mapping: Option<Behavior>,
+    pub filtering: Option<Behavior>,
+}
+
+impl NatBehavior {
+    pub fn nat_type(&self) -> NatType {
+        match (self.mapping, self.filtering) {
+            (Some(Behavior::EndpointIndependent), Some(Behavior::EndpointIndependent)) => {
+                NatType::FullCone
+            }
+            (Some(Behavior::EndpointIndependent), Some(Behavior::AddressDependent)) => {
+                NatType::Restricted
+            }
+            (Some(Behavior::EndpointIndependent), Some(Behavior::AddressAndPortDependent)) => {
+                NatType::PortRestricted
+            }
+            (Some(Behavior::EndpointIndependent), None) => NatType::Unknown,
+            (Some(_), _) => NatType::Symmetric,
+            (None, _) => NatType::Unknown,
+        }
+    }
+}
+
+// mapping is tested over TCP from the mapped port itself; filtering needs CHANGE-REQUEST,
+// which only exists for UDP, so it describes the NAT's UDP policy
+// the first test is the binding that just produced the mapping; repeating it from the same
+// port would collide with that connection's TIME_WAIT
+pub async fn detect(
+    config: &GeneralConfig,
+    local_port: u16,
+    primary: SocketAddr,
+    response: &[u8],
+) -> NatBehavior {
+    let (mapping, filtering) = tokio::join!(
+        test_mapping(config, local_port, primary, response),
+        test_filtering(config)
+    );
+
+    let mapping = mapping.unwrap_or_else(|e| {
+        tracing::warn!("NAT mapping test failed: {}", e);
+        None
+    });
+    let filtering = filtering.unwrap_or_else(|e| {
+        tracing::warn!("NAT filtering test failed: {}", e);
+        None
+    });
+    NatBehavior { mapping, filtering }
+}
+
+// RFC 5780 section 4.3, falling back to the configured second endpoint when the server
+// does not announce an OTHER-ADDRESS
+async fn test_mapping(
+    config: &GeneralConfig,
+    local_port: u16,
+    primary: SocketAddr,
+    response: &[u8],
+) -> Result<Option<Behavior>, StunError> {
+    let mapped = message::parse_addr(response)?;
+    let other = other_address(response);
+
+    let Some(other) = other else {
+        let host = config
+            .nat_check_server_host
+            .as_deref()
+            .unwrap_or(&config.stun_server_host);
+        let server = lookup_server(host, config.nat_check_server_port, false).await?;
+        let (second, _) = tcp_binding(config, server, local_port).await?;
+        return Ok(Some(if second == mapped {
+            Behavior::EndpointIndependent
+        } else if server.ip() == primary.ip() {
+            Behavior::AddressAndPortDependent
+        } else {
+            // a different host cannot tell whether the port mattered as well
+            Behavior::AddressDependent
+        }));
+    };
+
+    let (second, _) = tcp_binding(
+        config,
+        SocketAddr::new(other.ip(), primary.port()),
+        local_port,
+    )
+    .await?;
+    if second == mapped {
+        return Ok(Some(Behavior::EndpointIndependent));
+    }
+    let (third, _) = tcp_binding(config, other, local_port).await?;
+    Ok(Some(if third == second {
+        Behavior::AddressDependent
+    } else {
+        Behavior::AddressAndPortDependent
+    }))
+}
+
+async fn tcp_binding(
+    config: &GeneralConfig,
+    server: SocketAddr,
+    local_port: u16,
+) -> Result<(SocketAddr, Option<SocketAddr>), StunError> {
+    let stream = stun_connect(server, local_port, &config.stun_keepalive).await?;
+    let response = tokio::time::timeout(TCP_TIMEOUT, binding_request_raw(stream, config))
+        .await
+        .map_err(|_| StunError::Timeout("the NAT mapping test response"))??;
+    Ok((message::parse_addr(&response)?, other_address(&response)))
+}
+
+// RFC 5780 section 4.4
+async fn test_filtering(config: &GeneralConfig) -> Result<Option<Behavior>, StunError> {
+    let server = lookup_server(&config.stun_server_host, config.stun_server_port, false).await?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+
+    let response = udp_binding(&socket, server, 0)
+        .await?
+        .ok_or(StunError::Timeout("a UDP binding response"))?;
+    if other_address(&response).is_none() {
+        tracing::info!(
+            "{} does not support RFC 5780, NAT filtering cannot be tested",
+            server
+        );
+        return Ok(None);
+    }
+
+    if udp_binding(&socket, server, CHANGE_IP | CHANGE_PORT)
+        .await?
+        .is_some()
+    {
+        return Ok(Some(Behavior::EndpointIndependent));
+    }
+    if udp_binding(&socket, server, CHANGE_PORT).await?.is_some() {
+        return Ok(Some(Behavior::AddressDependent));
+    }
+    Ok(Some(Behavior::AddressAndPortDependent))
+}
+
+// None when no answer arrived, which is the expected outcome of a filtered test
+async fn udp_binding(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change: u8,
+) -> Result<Option<Vec<u8>>, StunError> {
+    let transaction_id = message::transaction_id();
+    let request = MessageBuilder::new(message::BINDING_REQUEST, transaction_id)
+        .attr(message::ATTR_CHANGE_REQUEST, &[0, 0, 0, change])
+        .finish();
+
+    let mut buf = [0u8; 1500];
+    for _ in 0..UDP_ATTEMPTS {
+        socket.send_to(&request, server).await?;
+        let deadline = tokio::time::Instant::now() + UDP_WAIT;
+        // the answer may come from the alternate address, so accept any source
+        while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (n, _) = res?;
+            let response = &buf[..n];
+            if message::validate(response).is_err()
+                || message::transaction_id_of(response) != transaction_id
+            {
+                continue;
+            }
+            if message::is_error(response) {
+                let (code, reason) = message::error_code(response).unwrap_or((0, String::new()));
+                return Err(StunError::ErrorResponse { code, reason });
+            }
+            return Ok(Some(response.to_vec()));
+        }
+    }
+    Ok(None)
+}
+
+// RFC 3489 servers call it CHANGED-ADDRESS, same encoding
+fn other_address(response: &[u8]) -> Option<SocketAddr> {
+    message::find_attr(response, message::ATTR_OTHER_ADDRESS)
+        .or_else(|| message::find_attr(response, message::ATTR_CHANGED_ADDRESS))
+        .and_then(|value| message::plain_addr(value).ok())
+}
@@ -0,0 +1,178 @@
+use std::net::{SocketAddr, SocketAddrV4};
+
+use anyhow::bail;
+use chrono::Local;
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+use super::{
+    StunError,
+    client::{Client, ClientConfig, LongTermAuth},
+    lookup_server, message, software,
+};
+use crate::{
+    LAST_STUN_SUCCESS,
+    config::{GeneralConfig, TurnConfig},
+    copy, ddns,
+};
+
+const TRANSPORT_TCP: [u8; 4] = [6, 0, 0, 0];
+// permissions expire after five minutes regardless of the allocation lifetime
+const PERMISSION_REFRESH: u64 = 240;
+const RELAY_BUFFER_SIZE: usize = 8192;
+
+async fn connect(server: SocketAddr) -> anyhow::Result<TcpStream> {
+    Ok(tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        TcpStream::connect(server),
+    )
+    .await??)
+}
+
+async fn create_permissions(client: &mut Client, peers: &[SocketAddrV4]) -> anyhow::Result<()> {
+    if peers.is_empty() {
+        return Ok(());
+    }
+
+    let encoded: Vec<[u8; 8]> = peers.iter().map(|p| message::encode_xor_addr(*p)).collect();
+    let attrs: Vec<(u16, &[u8])> = encoded
+        .iter()
+        .map(|v| (message::ATTR_XOR_PEER_ADDRESS, v.as_slice()))
+        .collect();
+    client
+        .request(message::CREATE_PERMISSION_REQUEST, &attrs)
+        .await?;
+
+    Ok(())
+}
+
+async fn accept_connection(
+    server: SocketAddr,
+    config: ClientConfig,
+    connection_id: Vec<u8>,
+    local_port: u16,
+) -> anyhow::Result<()> {
+    let mut client = Client::new(connect(server).await?, config);
+    client
+        .request(
+            message::CONNECTION_BIND_REQUEST,
+            &[(message::ATTR_CONNECTION_ID, &connection_id)],
+        )
+        .await?;
+
+    // after CONNECTION-BIND the data connection carries the peer's raw bytes
+    let (mut relayed, leftover) = client.into_parts();
+    let mut local = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], local_port))).await?;
+    local.write_all(&leftover).await?;
+
+    let transferred = copy::Transferred::default();
+    copy::copy_bidirectional(
+        &mut relayed,
+        &mut local,
+        RELAY_BUFFER_SIZE,
+        None,
+        &transferred,
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn run(
+    config: &TurnConfig,
+    general: &GeneralConfig,
+    local_port: u16,
+    last_addr: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    tracing::warn!(
+        "Direct traversal is not possible, falling back to TURN relay {}:{}",
+        config.server_host,
+        config.server_port
+    );
+
+    let server = lookup_server(&config.server_host, config.server_port, false).await?;
+    let auth = LongTermAuth {
+        username: config.username.clone(),
+        password: config.password.clone(),
+        realm: config.realm.clone(),
+    };
+    let mut control = Client::new(
+        connect(server).await?,
+        ClientConfig::new(software(general), Some(auth)),
+    );
+
+    let response = control
+        .request(
+            message::ALLOCATE_REQUEST,
+            &[
+                (message::ATTR_REQUESTED_TRANSPORT, &TRANSPORT_TCP),
+                (message::ATTR_LIFETIME, &config.lifetime.to_be_bytes()),
+            ],
+        )
+        .await?;
+
+    let relayed = message::find_attr(&response, message::ATTR_XOR_RELAYED_ADDRESS)
+        .ok_or_else(|| StunError::Parse("TURN allocation is missing XOR-RELAYED-ADDRESS".into()))
+        .and_then(|v| message::xor_addr(v, message::transaction_id_of(&response)))?;
+    let lifetime = message::find_attr(&response, message::ATTR_LIFETIME)
+        .and_then(|v| v.try_into().ok())
+        .map(u32::from_be_bytes)
+        .unwrap_or(config.lifetime);
+    tracing::info!("TURN relay allocated: {} (lifetime {}s)", relayed, lifetime);
+
+    let peers: Vec<SocketAddrV4> = config
+        .peers
+        .iter()
+        .map(|ip| SocketAddrV4::new(*ip, 0))
+        .collect();
+    create_permissions(&mut control, &peers).await?;
+
+    *LAST_STUN_SUCCESS.write().await = Some(Local::now());
+    ddns::publish(&general.state_file, relayed, last_addr).await;
+
+    let refresh_every = (lifetime as u64 / 2).min(PERMISSION_REFRESH);
+    let mut refresh = tokio::time::interval(std::time::Duration::from_secs(refresh_every));
+    refresh.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = refresh.tick() => {
+                control
+                    .request(
+                        message::REFRESH_REQUEST,
+                        &[(message::ATTR_LIFETIME, &config.lifetime.to_be_bytes())],
+                    )
+                    .await?;
+                create_permissions(&mut control, &peers).await?;
+                *LAST_STUN_SUCCESS.write().await = Some(Local::now());
+                tracing::info!("TURN allocation refreshed.");
+            }
+            indication = control.next_indication() => {
+                let indication = indication?;
+                if message::msg_type(&indication) != message::CONNECTION_ATTEMPT_INDICATION {
+                    continue;
+                }
+                let Some(connection_id) =
+                    message::find_attr(&indication, message::ATTR_CONNECTION_ID)
+                else {
+                    bail!("CONNECTION-ATTEMPT without CONNECTION-ID");
+                };
+
+                if let Some(peer) = message::find_attr(&indication, message::ATTR_XOR_PEER_ADDRESS)
+                    .and_then(|v| message::xor_addr(v, message::transaction_id_of(&indication)).ok())
+                {
+                    tracing::info!("TURN connection attempt from {}", peer);
+                }
+
+                let connection_id = connection_id.to_vec();
+                let client_config = control.config();
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        accept_connection(server, client_config, connection_id, local_port).await
+                    {
+                        tracing::error!("TURN relayed session error: {}", e);
+                    }
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::{future::Future, time::Duration};
+
+use tokio::{
+    task::{AbortHandle, JoinHandle},
+    time::Instant,
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+// aborting the supervisor must take the running attempt down with it
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub fn spawn<F, Fut>(name: &'static str, mut factory: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let task = tokio::spawn(factory());
+            let _attempt = AbortOnDrop(task.abort_handle());
+            match task.await {
+                Ok(Ok(())) => tracing::warn!(task = name, "Background task exited unexpectedly"),
+                Ok(Err(e)) => tracing::error!(task = name, error = %e, "Background task failed"),
+                Err(e) if e.is_panic() => tracing::error!(task = name, "Background task panicked"),
+                Err(_) => {
+                    tracing::info!(task = name, "Background task cancelled");
+                    break;
+                }
+            }
+
+            // a task that ran for a while before failing gets a fresh backoff
+            if started.elapsed() >= STABLE_AFTER {
+                backoff = INITIAL_BACKOFF;
+            }
+
+            tracing::info!(
+                task = name,
+                "Restarting background task in {}s...",
+                backoff.as_secs()
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    })
+}
@@ -0,0 +1,30 @@
+use sd_notify::NotifyState;
+
+pub fn notify_ready() {
+    if let Err(e) = sd_notify::notify(&[NotifyState::Ready]) {
+        tracing::warn!("Failed to notify systemd readiness: {}", e);
+    }
+}
+
+pub fn notify_stopping() {
+    let _ = sd_notify::notify(&[NotifyState::Stopping]);
+}
+
+pub fn spawn_watchdog() {
+    let Some(timeout) = sd_notify::watchdog_enabled() else {
+        return;
+    };
+
+    // ping at half the deadline so a single slow tick doesn't trip the watchdog
+    let period = timeout / 2;
+    tracing::info!("systemd watchdog enabled, pinging every {:?}", period);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(period);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = sd_notify::notify(&[NotifyState::Watchdog]) {
+                tracing::warn!("Failed to send systemd watchdog ping: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,46 @@
+use std::sync::OnceLock;
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing_subscriber::{Layer, Registry, reload};
+
+type BoxedLayer = Option<Box<dyn Layer<Registry> + Send + Sync>>;
+pub type Handle = reload::Handle<BoxedLayer, Registry>;
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+// logging starts before the configuration is read, so the exporter is slotted in afterwards
+pub fn layer() -> (reload::Layer<BoxedLayer, Registry>, Handle) {
+    reload::Layer::new(None)
+}
+
+// must run outside the async runtime, the blocking HTTP client refuses to start inside one
+pub fn init(handle: &Handle, endpoint: &str) -> anyhow::Result<()> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("bedrockhole").build())
+        .build();
+
+    let tracer = provider.tracer("bedrockhole");
+    handle.reload(Some(
+        tracing_opentelemetry::layer().with_tracer(tracer).boxed(),
+    ))?;
+    let _ = PROVIDER.set(provider);
+
+    tracing::info!("Exporting traces to {}", endpoint);
+    Ok(())
+}
+
+// flushes spans still sitting in the batch queue
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        tracing::warn!("Failed to flush pending traces: {}", e);
+    }
+}
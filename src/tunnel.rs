@@ -0,0 +1,431 @@
+use std::{
+    io::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::bail;
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    config::{TunnelConfig, TunnelMode, TunnelRole},
+    copy::Transferred,
+    random,
+};
+
+const HELLO: &[u8; 4] = b"BHT1";
+const NONCE_LEN: usize = 32;
+const TAG_LEN: usize = 32;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// frame: u16 length of the sealed body, then the body; an empty payload ends the stream.
+// Without encryption the (compressed) bytes go out unframed
+const MAX_PAYLOAD: usize = 16 * 1024;
+const AEAD_TAG_LEN: usize = 16;
+// both sides derive the next key after this many frames, well before the 64-bit counter
+// could wrap, so no nonce is ever used twice under one key
+const REKEY_AFTER: u64 = 1 << 20;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+fn role_label(role: TunnelRole) -> &'static [u8] {
+    match role {
+        TunnelRole::Edge => b"edge",
+        TunnelRole::Home => b"home",
+    }
+}
+
+fn auth_tag(psk: &[u8], role: TunnelRole, transcript: &[u8]) -> HmacSha256 {
+    hmac(
+        psk,
+        &[b"bedrockhole tunnel auth", role_label(role), transcript],
+    )
+}
+
+fn derive_key(psk: &[u8], sender: TunnelRole, transcript: &[u8]) -> [u8; 32] {
+    hmac(
+        psk,
+        &[b"bedrockhole tunnel key", role_label(sender), transcript],
+    )
+    .finalize()
+    .into_bytes()
+    .into()
+}
+
+struct Direction {
+    key: [u8; 32],
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Direction {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.into()),
+            key,
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        if self.counter == REKEY_AFTER {
+            self.key = hmac(&self.key, &[b"bedrockhole tunnel rekey"])
+                .finalize()
+                .into_bytes()
+                .into();
+            self.cipher = ChaCha20Poly1305::new(&self.key.into());
+            self.counter = 0;
+        }
+
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce.into()
+    }
+
+    fn seal(&mut self, msg: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .encrypt(&nonce, Payload { msg, aad })
+            .map_err(|_| io::Error::other("Tunnel frame failed to encrypt"))
+    }
+
+    fn open(&mut self, msg: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt(&nonce, Payload { msg, aad })
+            .map_err(|_| crypto_error())
+    }
+}
+
+pub struct Session {
+    role: TunnelRole,
+    // (seal, open), present in encrypted mode
+    ciphers: Option<(Direction, Direction)>,
+    compression_level: Option<i32>,
+}
+
+// None when the tunnel neither encrypts nor compresses, leaving the stream untouched
+pub async fn establish(
+    stream: &mut TcpStream,
+    config: &TunnelConfig,
+) -> anyhow::Result<Option<Session>> {
+    let compression_level = config.compress.then_some(config.compression_level);
+    let ciphers = match config.mode {
+        TunnelMode::Plain if compression_level.is_none() => return Ok(None),
+        TunnelMode::Plain => None,
+        TunnelMode::Encrypted => {
+            let psk = config.psk.as_deref().unwrap_or_default();
+            Some(handshake(stream, config.role, psk.as_bytes()).await?)
+        }
+    };
+
+    Ok(Some(Session {
+        role: config.role,
+        ciphers,
+        compression_level,
+    }))
+}
+
+// both sides prove they hold the PSK over fresh nonces from each end, so a recorded
+// handshake cannot be replayed, and the per-direction keys are bound to those nonces
+async fn handshake(
+    stream: &mut TcpStream,
+    role: TunnelRole,
+    psk: &[u8],
+) -> anyhow::Result<(Direction, Direction)> {
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange(stream, role, psk)).await {
+        Ok(res) => res,
+        Err(_) => bail!("Tunnel handshake timed out"),
+    }
+}
+
+async fn exchange(
+    stream: &mut TcpStream,
+    role: TunnelRole,
+    psk: &[u8],
+) -> anyhow::Result<(Direction, Direction)> {
+    let own_nonce = random::bytes::<NONCE_LEN>();
+    let mut transcript = [0u8; NONCE_LEN * 2];
+    let mut peer_tag = [0u8; TAG_LEN];
+
+    match role {
+        TunnelRole::Edge => {
+            stream.write_all(HELLO).await?;
+            stream.write_all(&own_nonce).await?;
+
+            transcript[..NONCE_LEN].copy_from_slice(&own_nonce);
+            stream.read_exact(&mut transcript[NONCE_LEN..]).await?;
+            stream.read_exact(&mut peer_tag).await?;
+            if auth_tag(psk, TunnelRole::Home, &transcript)
+                .verify_slice(&peer_tag)
+                .is_err()
+            {
+                bail!("Tunnel peer failed authentication, check tunnel.psk on both ends");
+            }
+
+            let tag = auth_tag(psk, TunnelRole::Edge, &transcript).finalize();
+            stream.write_all(&tag.into_bytes()).await?;
+        }
+        TunnelRole::Home => {
+            let mut hello = [0u8; HELLO.len()];
+            stream.read_exact(&mut hello).await?;
+            if &hello != HELLO {
+                bail!("Client did not open a BedrockHole tunnel");
+            }
+
+            stream.read_exact(&mut transcript[..NONCE_LEN]).await?;
+            transcript[NONCE_LEN..].copy_from_slice(&own_nonce);
+            let tag = auth_tag(psk, TunnelRole::Home, &transcript).finalize();
+            stream.write_all(&own_nonce).await?;
+            stream.write_all(&tag.into_bytes()).await?;
+
+            stream.read_exact(&mut peer_tag).await?;
+            if auth_tag(psk, TunnelRole::Edge, &transcript)
+                .verify_slice(&peer_tag)
+                .is_err()
+            {
+                bail!("Tunnel peer failed authentication, check tunnel.psk on both ends");
+            }
+        }
+    }
+
+    let peer = match role {
+        TunnelRole::Edge => TunnelRole::Home,
+        TunnelRole::Home => TunnelRole::Edge,
+    };
+
+    Ok((
+        Direction::new(derive_key(psk, role, &transcript)),
+        Direction::new(derive_key(psk, peer, &transcript)),
+    ))
+}
+
+fn crypto_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Tunnel frame failed to decrypt")
+}
+
+// zstd flushes after every chunk so a lone keepalive is never held back waiting for more
+struct Compressor(zstd::stream::write::Encoder<'static, Vec<u8>>);
+
+impl Compressor {
+    fn new(level: i32) -> io::Result<Self> {
+        Ok(Self(zstd::stream::write::Encoder::new(Vec::new(), level)?))
+    }
+
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.0.write_all(data)?;
+        self.0.flush()?;
+        Ok(std::mem::take(self.0.get_mut()))
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        self.0.do_finish()?;
+        Ok(std::mem::take(self.0.get_mut()))
+    }
+}
+
+struct Decompressor(zstd::stream::write::Decoder<'static, Vec<u8>>);
+
+impl Decompressor {
+    fn new() -> io::Result<Self> {
+        Ok(Self(zstd::stream::write::Decoder::new(Vec::new())?))
+    }
+
+    fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.0.write_all(data)?;
+        self.0.flush()?;
+        Ok(std::mem::take(self.0.get_mut()))
+    }
+}
+
+async fn write_frame<W>(writer: &mut W, dir: &mut Direction, payload: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let len = ((payload.len() + AEAD_TAG_LEN) as u16).to_be_bytes();
+    let body = dir.seal(payload, &len)?;
+    writer.write_all(&len).await?;
+    writer.write_all(&body).await
+}
+
+// None once the peer's close frame arrives; a bare EOF without it means the stream was cut short
+async fn read_frame<R>(
+    reader: &mut R,
+    dir: &mut Direction,
+    buf: &mut [u8],
+) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len).await?;
+    let n = u16::from_be_bytes(len) as usize;
+    if !(AEAD_TAG_LEN..=buf.len()).contains(&n) {
+        return Err(crypto_error());
+    }
+    reader.read_exact(&mut buf[..n]).await?;
+
+    let payload = dir.open(&buf[..n], &len)?;
+    Ok((!payload.is_empty()).then_some(payload))
+}
+
+// plain side to tunnel side: compress, then seal
+async fn outbound<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    mut seal: Option<Direction>,
+    mut compressor: Option<Compressor>,
+    total: &AtomicU64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0u8; MAX_PAYLOAD];
+    loop {
+        let n = reader.read(&mut buf).await?;
+
+        let compressed;
+        let data = match &mut compressor {
+            Some(compressor) if n == 0 => {
+                compressed = compressor.finish()?;
+                &compressed[..]
+            }
+            Some(compressor) => {
+                compressed = compressor.compress(&buf[..n])?;
+                &compressed[..]
+            }
+            None => &buf[..n],
+        };
+        match &mut seal {
+            // incompressible input grows a little, so one chunk may need two frames
+            Some(dir) => {
+                for payload in data.chunks(MAX_PAYLOAD) {
+                    write_frame(writer, dir, payload).await?;
+                }
+            }
+            None => writer.write_all(data).await?,
+        }
+
+        if n == 0 {
+            if let Some(dir) = &mut seal {
+                write_frame(writer, dir, &[]).await?;
+            }
+            // propagate the FIN but keep the opposite direction flowing
+            match writer.shutdown().await {
+                Err(e) if e.kind() != io::ErrorKind::NotConnected => return Err(e),
+                _ => return Ok(()),
+            }
+        }
+        total.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+// tunnel side to plain side: open, then decompress
+async fn inbound<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    mut open: Option<Direction>,
+    mut decompressor: Option<Decompressor>,
+    total: &AtomicU64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0u8; MAX_PAYLOAD + AEAD_TAG_LEN];
+    loop {
+        let received = match &mut open {
+            Some(dir) => read_frame(reader, dir, &mut buf).await?,
+            None => match reader.read(&mut buf).await? {
+                0 => None,
+                n => Some(buf[..n].to_vec()),
+            },
+        };
+        let Some(data) = received else {
+            match writer.shutdown().await {
+                Err(e) if e.kind() != io::ErrorKind::NotConnected => return Err(e),
+                _ => return Ok(()),
+            }
+        };
+
+        let data = match &mut decompressor {
+            Some(decompressor) => decompressor.decompress(&data)?,
+            None => data,
+        };
+        writer.write_all(&data).await?;
+        total.fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+}
+
+// moves plain bytes through the tunnel leg, counting the bytes sent into and received from
+// the tunnel
+pub async fn carry<P>(
+    plain: P,
+    tunnel: &mut TcpStream,
+    session: Session,
+    sent: &AtomicU64,
+    received: &AtomicU64,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite,
+{
+    let Session {
+        ciphers,
+        compression_level,
+        ..
+    } = session;
+    let (seal, open) = ciphers.unzip();
+    let (compressor, decompressor) = match compression_level {
+        Some(level) => (Some(Compressor::new(level)?), Some(Decompressor::new()?)),
+        None => (None, None),
+    };
+
+    let (mut plain_read, mut plain_write) = tokio::io::split(plain);
+    let (mut tunnel_read, mut tunnel_write) = tunnel.split();
+
+    tokio::try_join!(
+        outbound(&mut plain_read, &mut tunnel_write, seal, compressor, sent),
+        inbound(
+            &mut tunnel_read,
+            &mut plain_write,
+            open,
+            decompressor,
+            received
+        )
+    )?;
+    Ok(())
+}
+
+// counts client to server as up, like relay::relay
+pub async fn relay(
+    client_stream: &mut TcpStream,
+    server_stream: &mut TcpStream,
+    session: Session,
+    transferred: &Transferred,
+) -> io::Result<()> {
+    let (up, down) = (&transferred.up, &transferred.down);
+    match session.role {
+        TunnelRole::Edge => carry(client_stream, server_stream, session, up, down).await,
+        TunnelRole::Home => carry(server_stream, client_stream, session, down, up).await,
+    }
+}
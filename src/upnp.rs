@@ -0,0 +1,77 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::anyhow;
+use chrono::Local;
+use igd_next::{
+    PortMappingProtocol, SearchOptions,
+    aio::{
+        Gateway,
+        tokio::{Tokio, search_gateway},
+    },
+};
+use tokio::{net::UdpSocket, sync::Mutex};
+
+use crate::{LAST_STUN_SUCCESS, config::GeneralConfig, ddns, reachability};
+
+const DESCRIPTION: &str = "BedrockHole";
+const RENEW_INTERVAL: u64 = 60;
+
+static MAPPING: Mutex<Option<(Gateway<Tokio>, u16)>> = Mutex::const_new(None);
+
+async fn local_ip_towards(gateway: SocketAddr) -> anyhow::Result<IpAddr> {
+    // connecting a UDP socket sends nothing but makes the kernel pick the outgoing interface
+    let socket = UdpSocket::bind((IpAddr::from([0, 0, 0, 0]), 0)).await?;
+    socket.connect(gateway).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+pub async fn run(config: GeneralConfig, local_port: u16) -> anyhow::Result<()> {
+    let mut options = SearchOptions::default();
+    options.timeout = Some(std::time::Duration::from_secs(5));
+    let gateway = search_gateway(options)
+        .await
+        .map_err(|e| anyhow!("UPnP gateway discovery failed: {}", e))?;
+    tracing::info!("Found UPnP gateway at {}", gateway.addr);
+
+    let local_addr = SocketAddr::new(local_ip_towards(gateway.addr).await?, local_port);
+    let mut last_addr = ddns::last_published(&config).await;
+
+    loop {
+        gateway
+            .add_port(
+                PortMappingProtocol::TCP,
+                local_port,
+                local_addr,
+                config.mapping_lease,
+                DESCRIPTION,
+            )
+            .await?;
+        *MAPPING.lock().await = Some((gateway.clone(), local_port));
+
+        let addr = SocketAddr::new(gateway.get_external_ip().await?, local_port);
+        tracing::info!("UPnP mapping {} -> {} active", addr, local_addr);
+        *LAST_STUN_SUCCESS.write().await = Some(Local::now());
+
+        if ddns::publish(&config.state_file, addr, last_addr).await {
+            if last_addr != Some(addr) {
+                reachability::spawn_check(&config, addr);
+            }
+            last_addr = Some(addr);
+        }
+
+        // renewing often also notices a changed external IP without waiting for the lease
+        let renew = (config.mapping_lease as u64 / 2).clamp(1, RENEW_INTERVAL);
+        tokio::time::sleep(std::time::Duration::from_secs(renew)).await;
+    }
+}
+
+pub async fn remove_mapping() {
+    let Some((gateway, port)) = MAPPING.lock().await.take() else {
+        return;
+    };
+
+    match gateway.remove_port(PortMappingProtocol::TCP, port).await {
+        Ok(()) => tracing::info!("Removed UPnP mapping for port {}", port),
+        Err(e) => tracing::warn!("Failed to remove UPnP mapping for port {}: {}", port, e),
+    }
+}
@@ -0,0 +1,567 @@
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use hickory_resolver::{
+    Resolver, TokioResolver,
+    config::{NameServerConfigGroup, ResolverConfig},
+    name_server::TokioConnectionProvider,
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    net::{TcpStream, lookup_host},
+    sync::RwLock,
+    task::JoinSet,
+    time::Instant,
+};
+
+use crate::{
+    circuit::CircuitBreaker,
+    config::{BalanceStrategy, ForwardConfig, UpstreamConfig},
+    random,
+};
+
+// RFC 8305 recommends 250ms between connection attempts
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+const RESOLVE_ATTEMPTS: u32 = 6;
+const RESOLVE_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const STALE_RETRY: Duration = Duration::from_secs(30);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// upstreams of the current pool, for metrics
+static REGISTRY: Mutex<Vec<Arc<Upstream>>> = Mutex::new(Vec::new());
+
+struct Resolved {
+    addrs: Arc<[SocketAddr]>,
+    valid_until: Option<Instant>,
+}
+
+pub struct UpstreamResolver {
+    host: String,
+    port: u16,
+    resolver: Option<TokioResolver>,
+    cache: RwLock<Resolved>,
+}
+
+impl UpstreamResolver {
+    pub async fn new(
+        host: &str,
+        port: u16,
+        dns_server: Option<SocketAddr>,
+    ) -> anyhow::Result<Self> {
+        let resolver = dns_server.map(|server| {
+            let group = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+            Resolver::builder_with_config(
+                ResolverConfig::from_parts(None, vec![], group),
+                TokioConnectionProvider::default(),
+            )
+            .build()
+        });
+
+        let mut res = Self {
+            host: host.to_string(),
+            port,
+            resolver,
+            cache: RwLock::new(Resolved {
+                addrs: Arc::from([]),
+                valid_until: None,
+            }),
+        };
+        let resolved = res.resolve_with_retry().await?;
+        res.cache = RwLock::new(resolved);
+
+        Ok(res)
+    }
+
+    async fn lookup(&self) -> anyhow::Result<Resolved> {
+        let (addrs, valid_until): (Vec<SocketAddr>, _) = match &self.resolver {
+            Some(resolver) => {
+                let lookup = resolver.lookup_ip(self.host.as_str()).await?;
+                let addrs = lookup
+                    .iter()
+                    .map(|ip| SocketAddr::new(ip, self.port))
+                    .collect();
+                (addrs, Some(Instant::from_std(lookup.valid_until())))
+            }
+            // the system resolver exposes no TTL, so its answer is kept for the process lifetime
+            None => (
+                lookup_host((self.host.as_str(), self.port))
+                    .await?
+                    .collect(),
+                None,
+            ),
+        };
+
+        if addrs.is_empty() {
+            return Err(anyhow!("No address found for {}:{}", self.host, self.port));
+        }
+
+        Ok(Resolved {
+            addrs: addrs.into(),
+            valid_until,
+        })
+    }
+
+    async fn resolve_with_retry(&self) -> anyhow::Result<Resolved> {
+        let mut backoff = RESOLVE_INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match self.lookup().await {
+                Ok(resolved) => {
+                    tracing::info!(
+                        "Resolved upstream {}:{} to {:?} (attempt {})",
+                        self.host,
+                        self.port,
+                        resolved.addrs,
+                        attempt
+                    );
+                    return Ok(resolved);
+                }
+                Err(e) if attempt < RESOLVE_ATTEMPTS => {
+                    tracing::warn!(
+                        "Failed to resolve upstream {}:{}: {}, retrying in {}s ({}/{})...",
+                        self.host,
+                        self.port,
+                        e,
+                        backoff.as_secs(),
+                        attempt,
+                        RESOLVE_ATTEMPTS
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    tracing::error!(
+                        "Giving up resolving upstream {}:{} after {} attempts: {}",
+                        self.host,
+                        self.port,
+                        attempt,
+                        e
+                    );
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    pub async fn addrs(&self) -> Arc<[SocketAddr]> {
+        {
+            let cache = self.cache.read().await;
+            if cache
+                .valid_until
+                .is_none_or(|valid_until| valid_until > Instant::now())
+            {
+                return cache.addrs.clone();
+            }
+        }
+
+        let mut cache = self.cache.write().await;
+        // another connection may have refreshed the cache while we waited for the lock
+        if cache
+            .valid_until
+            .is_some_and(|valid_until| valid_until > Instant::now())
+        {
+            return cache.addrs.clone();
+        }
+
+        match self.lookup().await {
+            Ok(resolved) => {
+                if resolved.addrs != cache.addrs {
+                    tracing::info!(
+                        "Upstream {}:{} re-resolved to {:?}",
+                        self.host,
+                        self.port,
+                        resolved.addrs
+                    );
+                }
+                *cache = resolved;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to re-resolve upstream {}:{}: {}, keeping {:?}",
+                    self.host,
+                    self.port,
+                    e,
+                    cache.addrs
+                );
+                cache.valid_until = Some(Instant::now() + STALE_RETRY);
+            }
+        }
+
+        cache.addrs.clone()
+    }
+}
+
+// unix targets are written as "unix:/path/to/socket" in place of a host name
+enum Endpoint {
+    Tcp(Box<UpstreamResolver>),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+pub enum UpstreamStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl UpstreamStream {
+    // tunnels and multiplexing need a TCP peer, validation keeps unix upstreams away from them
+    pub fn into_tcp(self) -> anyhow::Result<TcpStream> {
+        match self {
+            UpstreamStream::Tcp(stream) => Ok(stream),
+            #[cfg(unix)]
+            UpstreamStream::Unix(_) => Err(anyhow!("A unix socket upstream cannot carry a tunnel")),
+        }
+    }
+}
+
+pub struct Upstream {
+    endpoint: Endpoint,
+    active: AtomicUsize,
+    failures: AtomicU32,
+    healthy: AtomicBool,
+    breaker: CircuitBreaker,
+}
+
+impl Upstream {
+    pub fn name(&self) -> String {
+        match &self.endpoint {
+            Endpoint::Tcp(resolver) => format!("{}:{}", resolver.host, resolver.port),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    async fn connect(&self, config: &ForwardConfig) -> anyhow::Result<UpstreamStream> {
+        match &self.endpoint {
+            Endpoint::Tcp(resolver) => Ok(UpstreamStream::Tcp(
+                connect(&resolver.addrs().await, config).await?,
+            )),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(UpstreamStream::Unix(UnixStream::connect(path).await?)),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        if !self.healthy.swap(true, Ordering::Relaxed) {
+            tracing::info!("Upstream {} is healthy again", self.name());
+        }
+    }
+
+    fn record_failure(&self, threshold: u32) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= threshold && self.healthy.swap(false, Ordering::Relaxed) {
+            tracing::warn!(
+                "Upstream {} marked unhealthy after {} consecutive failures",
+                self.name(),
+                failures
+            );
+        }
+    }
+}
+
+pub fn registered() -> Vec<Arc<Upstream>> {
+    REGISTRY.lock().unwrap().clone()
+}
+
+// counts the session against its upstream for least_connections until dropped
+pub struct UpstreamLease(Arc<Upstream>);
+
+impl UpstreamLease {
+    pub fn upstream(&self) -> &Upstream {
+        &self.0
+    }
+}
+
+impl Drop for UpstreamLease {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct UpstreamPool {
+    upstreams: Vec<Arc<Upstream>>,
+    strategy: BalanceStrategy,
+    unhealthy_threshold: u32,
+    next: AtomicUsize,
+    // hostname from the Minecraft handshake -> pool serving it
+    routes: HashMap<String, UpstreamPool>,
+}
+
+async fn probe_unhealthy(upstreams: Vec<Weak<Upstream>>, config: ForwardConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.health_probe_interval));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        // the pool is gone once the forward worker restarted
+        if upstreams.iter().all(|u| u.strong_count() == 0) {
+            return;
+        }
+        for upstream in upstreams.iter().filter_map(Weak::upgrade) {
+            if upstream.is_healthy() {
+                continue;
+            }
+            match tokio::time::timeout(PROBE_TIMEOUT, upstream.connect(&config)).await {
+                Ok(Ok(_)) => upstream.record_success(),
+                Ok(Err(e)) => {
+                    tracing::debug!("Health probe of {} failed: {}", upstream.name(), e)
+                }
+                Err(_) => tracing::debug!("Health probe of {} timed out", upstream.name()),
+            }
+        }
+    }
+}
+
+impl UpstreamPool {
+    pub async fn new(config: &ForwardConfig) -> anyhow::Result<Self> {
+        let mut pool = Self::with_targets(config, config.targets()).await?;
+        for (hostname, target) in &config.routes {
+            pool.routes.insert(
+                hostname.to_ascii_lowercase(),
+                Self::with_targets(config, vec![target.clone()]).await?,
+            );
+        }
+
+        *REGISTRY.lock().unwrap() = pool
+            .upstreams
+            .iter()
+            .chain(pool.routes.values().flat_map(|r| r.upstreams.iter()))
+            .cloned()
+            .collect();
+        Ok(pool)
+    }
+
+    async fn with_targets(
+        config: &ForwardConfig,
+        targets: Vec<UpstreamConfig>,
+    ) -> anyhow::Result<Self> {
+        let mut upstreams = Vec::new();
+        for target in targets {
+            let endpoint = match target.host.strip_prefix("unix:") {
+                #[cfg(unix)]
+                Some(path) => Endpoint::Unix(PathBuf::from(path)),
+                _ => Endpoint::Tcp(Box::new(
+                    UpstreamResolver::new(&target.host, target.port, config.dns_server).await?,
+                )),
+            };
+            upstreams.push(Arc::new(Upstream {
+                endpoint,
+                active: AtomicUsize::new(0),
+                failures: AtomicU32::new(0),
+                healthy: AtomicBool::new(true),
+                breaker: CircuitBreaker::new(config.circuit_breaker),
+            }));
+        }
+
+        tokio::spawn(probe_unhealthy(
+            upstreams.iter().map(Arc::downgrade).collect(),
+            config.clone(),
+        ));
+
+        Ok(Self {
+            upstreams,
+            strategy: config.balance,
+            unhealthy_threshold: config.unhealthy_threshold,
+            next: AtomicUsize::new(0),
+            routes: HashMap::new(),
+        })
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.upstreams.iter().map(|u| u.name()).collect()
+    }
+
+    pub fn has_routes(&self) -> bool {
+        !self.routes.is_empty()
+    }
+
+    // unknown hostnames and clients without a readable handshake land on the default pool
+    pub fn route(&self, hostname: Option<&str>) -> &UpstreamPool {
+        hostname.and_then(|h| self.routes.get(h)).unwrap_or(self)
+    }
+
+    // the strategy picks the first candidate; the rest are failover in pool order
+    fn candidates(&self) -> Vec<Arc<Upstream>> {
+        let n = self.upstreams.len();
+        let start = match self.strategy {
+            BalanceStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % n,
+            BalanceStrategy::Random => u64::from_be_bytes(random::bytes()) as usize % n,
+            BalanceStrategy::LeastConnections => (0..n)
+                .filter(|&i| self.upstreams[i].is_healthy())
+                .min_by_key(|&i| self.upstreams[i].active.load(Ordering::Relaxed))
+                .unwrap_or(0),
+        };
+
+        let mut order: Vec<Arc<Upstream>> = (0..n)
+            .map(|i| self.upstreams[(start + i) % n].clone())
+            .collect();
+        // unhealthy upstreams are only tried as a last resort when nothing else is up
+        if order.iter().any(|u| u.is_healthy()) {
+            order.retain(|u| u.is_healthy());
+        }
+        order
+    }
+
+    pub async fn connect(
+        &self,
+        config: &ForwardConfig,
+    ) -> anyhow::Result<(UpstreamStream, UpstreamLease)> {
+        let mut last_err = None;
+        for upstream in self.candidates() {
+            if !upstream.breaker.allow(&upstream.name()) {
+                continue;
+            }
+            match upstream.connect(config).await {
+                Ok(stream) => {
+                    upstream.breaker.on_success(&upstream.name());
+                    upstream.record_success();
+                    upstream.active.fetch_add(1, Ordering::Relaxed);
+                    return Ok((stream, UpstreamLease(upstream)));
+                }
+                Err(e) => {
+                    tracing::warn!("Upstream {} failed: {}", upstream.name(), e);
+                    upstream.breaker.on_failure(&upstream.name());
+                    upstream.record_failure(self.unhealthy_threshold);
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err
+            .unwrap_or_else(|| anyhow!("Circuit open for every upstream, rejecting connection")))
+    }
+}
+
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6());
+    v6.reverse();
+    v4.reverse();
+
+    let mut res = Vec::with_capacity(addrs.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        res.extend(v6.pop());
+        res.extend(v4.pop());
+    }
+
+    res
+}
+
+async fn happy_eyeballs<F, Fut>(addrs: &[SocketAddr], connect: F) -> anyhow::Result<TcpStream>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = anyhow::Result<TcpStream>> + Send + 'static,
+{
+    let mut pending = interleave(addrs).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => {
+                    attempts.spawn(connect(addr));
+                }
+                None => {
+                    return Err(last_err.unwrap_or_else(|| anyhow!("No upstream address")));
+                }
+            }
+        }
+
+        tokio::select! {
+            Some(res) = attempts.join_next() => {
+                let res = match res {
+                    Ok(res) => res,
+                    // only a runtime shutting down cancels an attempt, and panics abort the process
+                    Err(e) if e.is_cancelled() => Err(anyhow!("Connect attempt was cancelled")),
+                    Err(e) => std::panic::resume_unwind(e.into_panic()),
+                };
+                match res {
+                    // dropping the JoinSet aborts the attempts that lost the race
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => {
+                        last_err = Some(e);
+                        if let Some(addr) = pending.next() {
+                            attempts.spawn(connect(addr));
+                        }
+                    }
+                }
+            }
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.spawn(connect(addr));
+                }
+            }
+        }
+    }
+}
+
+async fn connect(addrs: &[SocketAddr], config: &ForwardConfig) -> anyhow::Result<TcpStream> {
+    let proxy = config.upstream_proxy.clone();
+
+    happy_eyeballs(addrs, move |addr| {
+        let proxy = proxy.clone();
+        async move {
+            match proxy {
+                Some(proxy) => proxy.connect(addr).await,
+                None => Ok(TcpStream::connect(addr).await?),
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_attempt_starts_the_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        let hanging: SocketAddr = "127.0.0.2:1".parse().unwrap();
+        let refused: SocketAddr = "127.0.0.3:1".parse().unwrap();
+
+        let started = Instant::now();
+        let stream = happy_eyeballs(&[hanging, refused, reachable], move |addr| async move {
+            if addr == hanging {
+                std::future::pending::<()>().await;
+            }
+            if addr == refused {
+                return Err(anyhow!("connection to {} refused", addr));
+            }
+            Ok(TcpStream::connect(addr).await?)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(stream.peer_addr().unwrap(), reachable);
+        // the third attempt follows the failure instead of waiting out another delay
+        assert!(started.elapsed() < ATTEMPT_DELAY * 3 / 2);
+    }
+}